@@ -1,121 +1,209 @@
-use bitcoin::consensus::encode;
-use bitcoin::key::Keypair;
-use bitcoin::secp256k1::schnorr::Signature;
-use bitcoin::secp256k1::XOnlyPublicKey;
-use bitcoin::secp256k1::{Message, Secp256k1};
-use bitcoin::sighash::TapSighashType;
-use bitcoin::taproot::TaprootBuilder;
-use bitcoin::TapSighash;
-use bitcoin::{Address, Network, ScriptBuf, Transaction, Witness};
-
-pub fn mulsig_address(multisign: Option<String>, private_key_u8: &[u8]) -> String {
+use bitcoin::key::{Keypair, TapTweak};
+use bitcoin::psbt::Psbt;
+use bitcoin::secp256k1::{Message, PublicKey, Secp256k1, XOnlyPublicKey};
+use bitcoin::sighash::{Prevouts, SighashCache, TapSighashType};
+use bitcoin::taproot;
+use bitcoin::{Address, Network, TapLeafHash, Transaction, TxOut, Witness};
+use std::str::FromStr;
+
+use crate::error::{Result, SelfCustodyError};
+use crate::script::{required_signatures, script_keys};
+
+pub fn keypair_from_secret(secret: &[u8]) -> Result<Keypair> {
     let secp = Secp256k1::new();
-    let keypair = Keypair::from_seckey_slice(&secp, private_key_u8).unwrap();
+    Keypair::from_seckey_slice(&secp, secret)
+        .map_err(|e| SelfCustodyError::InvalidKey(e.to_string()))
+}
 
-    multisign.unwrap_or(hex::encode(keypair.public_key().serialize()))
+pub fn parse_public_key(key: &str) -> Result<PublicKey> {
+    PublicKey::from_str(key).map_err(|e| SelfCustodyError::InvalidKey(e.to_string()))
 }
 
-pub fn build_p2tr_tweaked(
-    combined_xonly: XOnlyPublicKey,
-    mulsig_script: ScriptBuf,
-    escape_script: ScriptBuf,
-    network: Network,
-) -> Address {
-    let secp = Secp256k1::new();
-    let taproot_spend_info = TaprootBuilder::new()
-        .add_leaf(1, escape_script)
-        .expect("adding leaf should work")
-        .add_leaf(1, mulsig_script)
-        .expect("adding leaf should work")
-        .finalize(&secp, combined_xonly)
-        .expect("finalizing taproot builder should work");
-
-    Address::p2tr_tweaked(taproot_spend_info.output_key(), network)
+/// Parses `address` and checks it belongs to `network`.
+pub fn parse_address(address: &str, network: Network) -> Result<Address> {
+    Address::from_str(address)
+        .map_err(|e| SelfCustodyError::InvalidAddress(e.to_string()))?
+        .require_network(network)
+        .map_err(|_| SelfCustodyError::NetworkMismatch {
+            expected: network,
+            found: address.to_string(),
+        })
 }
 
-pub fn calculate_fee(virtual_size: usize, rate: f64, multiplier: f64) -> u64 {
-    let kilo_bytes = virtual_size as f64 / 1000_f64;
-    let rate = bitcoin::Amount::from_btc(rate).unwrap().to_sat() as f64;
-    ((kilo_bytes * rate) * multiplier).round() as u64
+pub fn mulsig_address(multisign: Option<String>, private_key_u8: &[u8]) -> Result<String> {
+    let keypair = keypair_from_secret(private_key_u8)?;
+
+    Ok(multisign.unwrap_or(hex::encode(keypair.public_key().serialize())))
 }
 
-pub fn sign_transaction(sighash: Vec<TapSighash>, secret: &[u8]) -> Vec<Signature> {
-    let secp = Secp256k1::new();
-    let keypair = Keypair::from_seckey_slice(&secp, secret).unwrap();
-    sighash
-        .into_iter()
-        .map(|v| secp.sign_schnorr(&Message::from(v), &keypair))
+pub(crate) fn psbt_prevouts(psbt: &Psbt) -> Result<Vec<TxOut>> {
+    psbt.inputs
+        .iter()
+        .enumerate()
+        .map(|(i, input)| {
+            input
+                .witness_utxo
+                .clone()
+                .ok_or(SelfCustodyError::MissingWitnessUtxo(i))
+        })
         .collect()
 }
 
-pub fn combine_multi_sign_transaction(
-    unsigned_tx: Transaction,
-    sig1s: Vec<Signature>,
-    sig2s: Vec<Signature>,
-) -> String {
-    let mut unsigned_tx = unsigned_tx;
-    assert_eq!(unsigned_tx.input.len(), sig1s.len());
-    assert_eq!(unsigned_tx.input.len(), sig2s.len());
-    // get witness
-    // insert signatures
-    for i in 0..unsigned_tx.input.len() {
-        let mut witness = Witness::new();
-        witness.push(
-            bitcoin::taproot::Signature {
-                sig: sig1s[i],
-                hash_ty: TapSighashType::Default,
+/// Adds a signature for every input where `secret` is one of the keys of the leaf to spend, or
+/// the internal key when the PSBT spends through the key path.
+pub fn sign_psbt(psbt: &mut Psbt, secret: &[u8]) -> Result<()> {
+    let secp = Secp256k1::new();
+    let keypair = keypair_from_secret(secret)?;
+    let xonly = keypair.x_only_public_key().0;
+
+    let prevouts = psbt_prevouts(psbt)?;
+    let mut sighasher = SighashCache::new(&psbt.unsigned_tx);
+
+    for (i, input) in psbt.inputs.iter_mut().enumerate() {
+        let hash_ty = match input.sighash_type {
+            Some(t) => t
+                .taproot_hash_ty()
+                .map_err(|e| SelfCustodyError::InvalidPsbt(e.to_string()))?,
+            None => TapSighashType::Default,
+        };
+
+        if input.tap_scripts.is_empty() {
+            if input.tap_internal_key != Some(xonly) {
+                continue;
             }
-            .to_vec(),
-        );
-        witness.push(
-            bitcoin::taproot::Signature {
-                sig: sig2s[i],
-                hash_ty: TapSighashType::Default,
+            let sighash = sighasher
+                .taproot_key_spend_signature_hash(i, &Prevouts::All(&prevouts), hash_ty)
+                .map_err(|e| SelfCustodyError::Sighash(e.to_string()))?;
+            let tweaked: Keypair = keypair.tap_tweak(&secp, input.tap_merkle_root).into();
+            input.tap_key_sig = Some(taproot::Signature {
+                sig: secp.sign_schnorr(&Message::from(sighash), &tweaked),
+                hash_ty,
+            });
+            continue;
+        }
+
+        for (script, version) in input.tap_scripts.values() {
+            if !script_keys(script).contains(&xonly) {
+                continue;
             }
-            .to_vec(),
-        );
-        witness.push(unsigned_tx.input[i].witness.second_to_last().unwrap());
-        witness.push(unsigned_tx.input[i].witness.last().unwrap());
-        unsigned_tx.input[i].witness = witness;
+            let leaf_hash = TapLeafHash::from_script(script, *version);
+            let sighash = sighasher
+                .taproot_script_spend_signature_hash(
+                    i,
+                    &Prevouts::All(&prevouts),
+                    leaf_hash,
+                    hash_ty,
+                )
+                .map_err(|e| SelfCustodyError::Sighash(e.to_string()))?;
+            input.tap_script_sigs.insert(
+                (xonly, leaf_hash),
+                taproot::Signature {
+                    sig: secp.sign_schnorr(&Message::from(sighash), &keypair),
+                    hash_ty,
+                },
+            );
+        }
     }
 
-    encode::serialize_hex(&unsigned_tx)
+    Ok(())
 }
 
-pub fn combine_escape_transaction(unsigned_tx: Transaction, sig1s: Vec<Signature>) -> String {
-    let mut unsigned_tx = unsigned_tx;
-    assert_eq!(unsigned_tx.input.len(), sig1s.len());
-    for i in 0..unsigned_tx.input.len() {
-        let mut witness = Witness::new();
-        witness.push(
-            bitcoin::taproot::Signature {
-                sig: sig1s[i],
-                hash_ty: TapSighashType::Default,
-            }
-            .to_vec(),
-        );
-        witness.push(unsigned_tx.input[i].witness.second_to_last().unwrap());
-        witness.push(unsigned_tx.input[i].witness.last().unwrap());
-        unsigned_tx.input[i].witness = witness;
+/// Checks every signature collected in the PSBT against its sighash, returning the input index,
+/// the signing key and whether the signature is valid.
+pub fn verify_psbt_signatures(psbt: &Psbt) -> Result<Vec<(usize, XOnlyPublicKey, bool)>> {
+    let secp = Secp256k1::verification_only();
+    let prevouts = psbt_prevouts(psbt)?;
+    let mut sighasher = SighashCache::new(&psbt.unsigned_tx);
+
+    let mut results = vec![];
+    for (i, input) in psbt.inputs.iter().enumerate() {
+        if let (Some(sig), Some(internal_key)) = (input.tap_key_sig, input.tap_internal_key) {
+            let output_key = internal_key.tap_tweak(&secp, input.tap_merkle_root).0.to_inner();
+            let sighash = sighasher
+                .taproot_key_spend_signature_hash(i, &Prevouts::All(&prevouts), sig.hash_ty)
+                .map_err(|e| SelfCustodyError::Sighash(e.to_string()))?;
+            let valid = secp
+                .verify_schnorr(&sig.sig, &Message::from(sighash), &output_key)
+                .is_ok();
+            results.push((i, output_key, valid));
+        }
+        for ((key, leaf_hash), sig) in input.tap_script_sigs.iter() {
+            let sighash = sighasher
+                .taproot_script_spend_signature_hash(
+                    i,
+                    &Prevouts::All(&prevouts),
+                    *leaf_hash,
+                    sig.hash_ty,
+                )
+                .map_err(|e| SelfCustodyError::Sighash(e.to_string()))?;
+            let valid = secp
+                .verify_schnorr(&sig.sig, &Message::from(sighash), key)
+                .is_ok();
+            results.push((i, *key, valid));
+        }
     }
 
-    encode::serialize_hex(&unsigned_tx)
+    Ok(results)
 }
 
-pub fn combine_key_transaction(unsigned_tx: Transaction, sig1s: Vec<Signature>) -> String {
-    let mut unsigned_tx = unsigned_tx;
-    assert_eq!(unsigned_tx.input.len(), sig1s.len());
-    for i in 0..unsigned_tx.input.len() {
+/// Builds the final witness of every input from the collected signatures and extracts the
+/// transaction. Signatures are pushed in reverse key order so the first key of the leaf script
+/// checks the top of the stack.
+pub fn finalize_psbt(psbt: Psbt) -> Result<Transaction> {
+    let mut psbt = psbt;
+    for (i, input) in psbt.inputs.iter_mut().enumerate() {
         let mut witness = Witness::new();
-        witness.push(
-            bitcoin::taproot::Signature {
-                sig: sig1s[i],
-                hash_ty: TapSighashType::Default,
+        if let Some(sig) = input.tap_key_sig {
+            witness.push(sig.to_vec());
+        } else {
+            let mut missing = None;
+            for (control_block, (script, version)) in input.tap_scripts.iter() {
+                let leaf_hash = TapLeafHash::from_script(script, *version);
+                let required = required_signatures(script);
+                // the first `required` signers in script order sign, the others push nothing
+                let mut found = 0;
+                let mut items: Vec<Vec<u8>> = script_keys(script)
+                    .iter()
+                    .map(|key| match input.tap_script_sigs.get(&(*key, leaf_hash)) {
+                        Some(sig) if found < required => {
+                            found += 1;
+                            sig.to_vec()
+                        }
+                        _ => vec![],
+                    })
+                    .collect();
+                if found != required {
+                    missing = Some(SelfCustodyError::SignatureCountMismatch {
+                        input: i,
+                        expected: required,
+                        found,
+                    });
+                    continue;
+                }
+                // the first key checks the top of the stack, the last witness item
+                items.reverse();
+                for item in items {
+                    witness.push(item);
+                }
+                witness.push(script);
+                witness.push(control_block.serialize());
+                break;
             }
-            .to_vec(),
-        );
-        unsigned_tx.input[i].witness = witness;
+            if witness.is_empty() {
+                return Err(missing.unwrap_or(SelfCustodyError::MissingWitnessTemplate(i)));
+            }
+        }
+
+        input.final_script_witness = Some(witness);
+        input.tap_key_sig = None;
+        input.tap_script_sigs.clear();
+        input.tap_scripts.clear();
+        input.tap_key_origins.clear();
+        input.tap_internal_key = None;
+        input.tap_merkle_root = None;
+        input.sighash_type = None;
     }
 
-    encode::serialize_hex(&unsigned_tx)
+    psbt.extract_tx()
+        .map_err(|e| SelfCustodyError::InvalidTransaction(e.to_string()))
 }
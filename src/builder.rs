@@ -1,175 +1,698 @@
 use bitcoin::absolute::LockTime;
 
-use bitcoin::secp256k1::schnorr::Signature;
-use bitcoin::secp256k1::XOnlyPublicKey;
-use std::str::FromStr;
-
-use crate::aux::calculate_fee;
+use crate::credential::{BurnOpReturnData, MapOpReturnData};
+use crate::error::{Result, SelfCustodyError};
+use crate::fee::Fee;
+use crate::policy::{CustodyPolicy, Leaf};
+use crate::script::{
+    relative_sequence, required_signatures, script_keys, script_timelock, LeafTimelock,
+};
+use crate::selection::{select_coins, CoinSelection, CoinSelectionAlgorithm, SelectionTarget};
 use crate::LocalUtxo;
-use bitcoin::secp256k1::Secp256k1;
-use bitcoin::sighash::{Prevouts, SighashCache, TapSighashType};
-use bitcoin::taproot::{LeafVersion, TaprootBuilder};
-use bitcoin::TapLeafHash;
-use bitcoin::TapSighash;
+use bitcoin::bip32::KeySource;
+use bitcoin::psbt::Psbt;
+use bitcoin::secp256k1::rand::seq::SliceRandom;
+use bitcoin::secp256k1::rand::thread_rng;
+use bitcoin::taproot::LeafVersion;
 use bitcoin::{
-    script, transaction, Address, Amount, Network, OutPoint, ScriptBuf, Sequence, TapNodeHash,
-    Transaction, TxIn, TxOut, Txid, Witness,
+    script, transaction, Address, Amount, ScriptBuf, Sequence, Transaction, TxIn, TxOut, VarInt,
+    Weight, Witness,
 };
 
+/// Non-final so `nLockTime` and `OP_CLTV` are enforced, and below `0xfffffffe` so the spend
+/// signals BIP-125 replaceability. The "no locktime" of its name is about relative locks.
+const LOCKTIME_AND_RBF: Sequence = Sequence::ENABLE_RBF_NO_LOCKTIME;
+
+/// A BIP-340 signature followed by a non-default sighash type byte.
+const MAX_TAPROOT_SIGNATURE_SIZE: usize = 65;
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum SpendType {
     MULSIG,
+    /// The `nLockTime` of the spend for an `OP_CLTV` escape leaf. An `OP_CSV` leaf reads its lock
+    /// from the script into the input `nSequence`, the value is unused and `nLockTime` is zero.
     ESCAPE(u32),
+    /// As `ESCAPE`, for the enforce leaf.
+    ENFORCE(u32),
+    /// The committee leaf of a one-way channel.
+    COMMITTEE,
     KEY,
 }
 
-pub(crate) fn build_tx(
+impl SpendType {
+    /// The tapleaf spent by this path, `None` for the key path.
+    pub fn leaf(&self) -> Option<Leaf> {
+        match self {
+            SpendType::MULSIG => Some(Leaf::Mulsig),
+            SpendType::ESCAPE(_) => Some(Leaf::Escape),
+            SpendType::ENFORCE(_) => Some(Leaf::Enforce),
+            SpendType::COMMITTEE => Some(Leaf::Committee),
+            SpendType::KEY => None,
+        }
+    }
+}
+
+/// Matches a utxo to the policy of the address it pays to. A utxo without `script_pubkey` is
+/// assumed to pay to the policy only when a single one is given.
+pub fn input_policy<'a>(
+    utxo: &LocalUtxo,
+    policies: &'a [CustodyPolicy],
+) -> Result<&'a CustodyPolicy> {
+    let first = first_policy(policies)?;
+    match utxo.script_pubkey()? {
+        None if policies.len() == 1 => Ok(first),
+        None => Err(SelfCustodyError::InvalidUtxo(format!(
+            "{}:{} has no script_pubkey to tell which of the {} custody policies it pays to",
+            utxo.txid,
+            utxo.vout,
+            policies.len()
+        ))),
+        Some(script_pubkey) => policies
+            .iter()
+            .find(|policy| policy.script_pubkey() == script_pubkey)
+            .ok_or(SelfCustodyError::InvalidUtxo(format!(
+                "{}:{} does not pay to any of the custody policies",
+                utxo.txid, utxo.vout
+            ))),
+    }
+}
+
+/// The order of the outputs of `build_tx`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputOrdering {
+    /// The recipients in the given order, followed by the change.
+    Given,
+    /// BIP-69: by amount, then by script, so the change can't be told apart by its position.
+    Bip69,
+    Random,
+}
+
+/// The outputs of `build_tx`.
+#[derive(Clone, Debug)]
+pub struct Payment {
+    recipients: Vec<(Address, Amount)>,
+    change: Option<Address>,
+    sweep: bool,
+    ordering: OutputOrdering,
+}
+
+impl Payment {
+    /// Pays every recipient, the change goes back to the first policy.
+    pub fn new(recipients: Vec<(Address, Amount)>) -> Self {
+        Payment {
+            recipients,
+            change: None,
+            sweep: false,
+            ordering: OutputOrdering::Given,
+        }
+    }
+
+    pub fn to(receiver: Address, amount: Amount) -> Self {
+        Self::new(vec![(receiver, amount)])
+    }
+
+    /// Sends everything but the fee to `receiver`, without change.
+    pub fn sweep(receiver: Address) -> Self {
+        Self::to(receiver, Amount::ZERO).with_sweep()
+    }
+
+    /// The last recipient takes what is left after the other recipients and the fee, its amount
+    /// is ignored and there is no change.
+    pub fn with_sweep(mut self) -> Self {
+        self.sweep = true;
+        self
+    }
+
+    /// Sends the change to `change`, e.g. a custody address with a later escape lock.
+    pub fn with_change(mut self, change: Address) -> Self {
+        self.change = Some(change);
+        self
+    }
+
+    pub fn with_ordering(mut self, ordering: OutputOrdering) -> Self {
+        self.ordering = ordering;
+        self
+    }
+
+    pub fn recipients(&self) -> &[(Address, Amount)] {
+        &self.recipients
+    }
+
+    pub fn change(&self) -> Option<&Address> {
+        self.change.as_ref()
+    }
+
+    pub fn is_sweep(&self) -> bool {
+        self.sweep
+    }
+
+    pub fn ordering(&self) -> OutputOrdering {
+        self.ordering
+    }
+
+    /// The outputs paid a fixed amount and the script of the output taking the rest.
+    fn outputs(&self, policies: &[CustodyPolicy]) -> Result<(Vec<TxOut>, ScriptBuf)> {
+        let mut outputs: Vec<TxOut> = self
+            .recipients
+            .iter()
+            .map(|(address, amount)| TxOut {
+                value: *amount,
+                script_pubkey: address.script_pubkey(),
+            })
+            .collect();
+        if self.sweep {
+            let swept = outputs
+                .pop()
+                .ok_or(SelfCustodyError::InvalidAddress("no recipient to sweep to".to_string()))?;
+            return Ok((outputs, swept.script_pubkey));
+        }
+        if outputs.is_empty() {
+            return Err(SelfCustodyError::InvalidAddress("no recipient given".to_string()));
+        }
+        let change = match &self.change {
+            Some(change) => change.script_pubkey(),
+            None => first_policy(policies)?.script_pubkey(),
+        };
+        Ok((outputs, change))
+    }
+}
+
+/// Spends `utxos` through `spent_type` to the outputs of `payment`. Every utxo is signed against
+/// the policy of its own address.
+pub fn build_tx(
     spent_type: SpendType,
-    combined_xonly: XOnlyPublicKey,
-    mulsig_script: ScriptBuf,
-    escape_script: ScriptBuf,
-    network: Network,
+    policies: &[CustodyPolicy],
+    utxos: Vec<LocalUtxo>,
+    fee: Fee,
+    payment: &Payment,
+) -> Result<Psbt> {
+    let (outputs, change) = payment.outputs(policies)?;
+    let mut psbt = build_custody_tx(
+        spent_type,
+        policies,
+        utxos,
+        fee,
+        outputs,
+        change,
+        payment.sweep,
+    )?;
+    // the psbt outputs carry no data yet, only the transaction outputs are moved
+    match payment.ordering {
+        OutputOrdering::Given => {}
+        OutputOrdering::Bip69 => psbt.unsigned_tx.output.sort_by(|a, b| {
+            a.value
+                .cmp(&b.value)
+                .then_with(|| a.script_pubkey.as_bytes().cmp(b.script_pubkey.as_bytes()))
+        }),
+        OutputOrdering::Random => psbt.unsigned_tx.output.shuffle(&mut thread_rng()),
+    }
+    Ok(psbt)
+}
+
+/// Burning credential transaction: the mapper and the committee co-sign through the mulsig
+/// leaf, output 0 pays `amount` to the mapper, output 1 carries the burn OP_RETURN and the
+/// change goes back to the mapping script of the first policy.
+pub fn build_burn_tx(
+    policies: &[CustodyPolicy],
     utxos: Vec<LocalUtxo>,
-    fee_rate: f64,
-    receiver: Address,
+    fee: Fee,
+    mapper: Address,
     amount: u64,
-) -> (Transaction, Vec<TapSighash>, Option<TapNodeHash>) {
-    let secp = Secp256k1::new();
-
-    let taproot_spend_info = TaprootBuilder::new()
-        .add_leaf(1, escape_script.clone())
-        .expect("adding leaf should work")
-        .add_leaf(1, mulsig_script.clone())
-        .expect("adding leaf should work")
-        .finalize(&secp, combined_xonly)
-        .expect("finalizing taproot builder should work");
-
-    let mut lock_time = LockTime::ZERO;
-    let script = match spent_type {
-        SpendType::MULSIG => mulsig_script.clone(),
-        SpendType::ESCAPE(t) => {
-            lock_time = LockTime::from_consensus(t);
-            escape_script.clone()
-        }
-        _ => escape_script.clone(),
+    data: &BurnOpReturnData,
+) -> Result<Psbt> {
+    let outputs = burn_outputs(&mapper, amount, data);
+    let change = first_policy(policies)?.script_pubkey();
+    build_custody_tx(SpendType::MULSIG, policies, utxos, fee, outputs, change, false)
+}
+
+/// Outputs 0 and 1 of a burning credential, `amount` to the mapper and the burn OP_RETURN.
+fn burn_outputs(mapper: &Address, amount: u64, data: &BurnOpReturnData) -> Vec<TxOut> {
+    vec![
+        TxOut {
+            value: Amount::from_sat(amount),
+            script_pubkey: mapper.script_pubkey(),
+        },
+        TxOut {
+            value: Amount::ZERO,
+            script_pubkey: data.script(),
+        },
+    ]
+}
+
+/// Forced withdrawal transaction: the committee spends `utxos` through the enforce leaf, unlocked
+/// at `enforce_time`, to a single output at the mapping script of `destination`. The fee is taken
+/// from that output and there is no OP_RETURN.
+pub fn build_forced_withdrawal_tx(
+    policies: &[CustodyPolicy],
+    utxos: Vec<LocalUtxo>,
+    fee: Fee,
+    enforce_time: u32,
+    destination: &CustodyPolicy,
+) -> Result<Psbt> {
+    build_custody_tx(
+        SpendType::ENFORCE(enforce_time),
+        policies,
+        utxos,
+        fee,
+        vec![],
+        destination.script_pubkey(),
+        true,
+    )
+}
+
+/// Selects the utxos `build_tx` spends for `payment`, see `select_coins`. A sweep spends every
+/// utxo, there is nothing to select.
+pub fn select_utxos(
+    spent_type: SpendType,
+    policies: &[CustodyPolicy],
+    utxos: Vec<LocalUtxo>,
+    fee: Fee,
+    payment: &Payment,
+    fallback: CoinSelectionAlgorithm,
+) -> Result<CoinSelection> {
+    if payment.sweep {
+        return Err(SelfCustodyError::InvalidUtxo(
+            "a sweep spends every utxo".to_string(),
+        ));
+    }
+    let (outputs, change) = payment.outputs(policies)?;
+    select_custody_utxos(spent_type, policies, utxos, fee, outputs, change, fallback)
+}
+
+/// Selects the utxos `build_burn_tx` spends to pay `amount` to `mapper`, see `select_coins`.
+pub fn select_burn_utxos(
+    policies: &[CustodyPolicy],
+    utxos: Vec<LocalUtxo>,
+    fee: Fee,
+    mapper: &Address,
+    amount: u64,
+    data: &BurnOpReturnData,
+    fallback: CoinSelectionAlgorithm,
+) -> Result<CoinSelection> {
+    let outputs = burn_outputs(mapper, amount, data);
+    let change = first_policy(policies)?.script_pubkey();
+    select_custody_utxos(SpendType::MULSIG, policies, utxos, fee, outputs, change, fallback)
+}
+
+/// Weighs every utxo with its predicted witness, the same weight as `build_custody_tx`, and
+/// selects against `outputs` followed by the change to `change`.
+fn select_custody_utxos(
+    spent_type: SpendType,
+    policies: &[CustodyPolicy],
+    utxos: Vec<LocalUtxo>,
+    fee: Fee,
+    mut outputs: Vec<TxOut>,
+    change: ScriptBuf,
+    fallback: CoinSelectionAlgorithm,
+) -> Result<CoinSelection> {
+    let change_dust = change.dust_value().to_sat();
+    let amount = outputs.iter().map(|v| v.value.to_sat()).sum();
+    outputs.push(TxOut {
+        value: Amount::ZERO,
+        script_pubkey: change,
+    });
+    let tx = Transaction {
+        version: transaction::Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: vec![],
+        output: outputs,
+    };
+    let target = SelectionTarget {
+        amount,
+        // the segwit marker and flag are counted for a transaction without inputs
+        base_weight: tx.weight().to_wu(),
+        change_dust,
+        fee,
     };
-    let control_block = taproot_spend_info
-        .control_block(&(script, LeafVersion::TapScript))
-        .expect("should compute control block");
 
-    let addr_self = Address::p2tr_tweaked(taproot_spend_info.output_key(), network);
+    // outpoint, empty script_sig and sequence
+    let input_weight = TxIn::default().base_size() as u64 * 4;
+    let candidates = utxos
+        .into_iter()
+        .map(|utxo| {
+            let policy = spend_policy(spent_type, &utxo, policies)?;
+            let witness = predict_satisfaction_weight(policy, spent_type.leaf())?;
+            Ok((utxo, input_weight + witness.to_wu()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    select_coins(candidates, &target, fallback)
+}
+
+/// The first of `policies`, the one the change goes back to.
+pub(crate) fn first_policy(policies: &[CustodyPolicy]) -> Result<&CustodyPolicy> {
+    policies
+        .first()
+        .ok_or(SelfCustodyError::InvalidUtxo("no custody policy given".to_string()))
+}
 
+/// Spends `utxos` through `spent_type` to `outputs`, followed by the change to `change` when it
+/// is above dust. With `sweep`, the change output is the one paid and can't be dropped.
+pub(crate) fn build_custody_tx(
+    spent_type: SpendType,
+    policies: &[CustodyPolicy],
+    utxos: Vec<LocalUtxo>,
+    fee: Fee,
+    outputs: Vec<TxOut>,
+    change: ScriptBuf,
+    sweep: bool,
+) -> Result<Psbt> {
+    let input_policies = utxos
+        .iter()
+        .map(|utxo| spend_policy(spent_type, utxo, policies))
+        .collect::<Result<Vec<_>>>()?;
+    let leaf_locks = input_policies
+        .iter()
+        .map(|policy| match spent_type.leaf() {
+            Some(leaf) => Ok(script_timelock(policy.script(leaf)?)),
+            None => Ok(None),
+        })
+        .collect::<Result<Vec<Option<LeafTimelock>>>>()?;
+    let lock_time = spend_lock_time(spent_type, &leaf_locks)?;
     let total_amount = utxos.iter().map(|v| v.amount).sum();
+    let amount: u64 = outputs.iter().map(|v| v.value.to_sat()).sum();
 
-    let spend_to_receiver = TxOut {
-        value: Amount::from_sat(amount),
-        script_pubkey: receiver.script_pubkey(),
-    };
     let spend_to_owner = TxOut {
         value: Amount::from_sat(total_amount),
-        script_pubkey: addr_self.script_pubkey(),
+        script_pubkey: change,
     };
+    let mut output = outputs;
+    output.push(spend_to_owner);
     let mut unsigned_tx = Transaction {
-        version: transaction::Version::ONE,
+        version: transaction::Version::TWO,
         lock_time,
         input: utxos
             .iter()
-            .map(|input| TxIn {
-                previous_output: OutPoint {
-                    txid: Txid::from_str(&input.txid).unwrap(),
-                    vout: input.vout,
-                },
-                script_sig: script::Builder::new().into_script(),
-                witness: Witness::new(),
-                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+            .zip(&leaf_locks)
+            .map(|(input, lock)| {
+                Ok(TxIn {
+                    previous_output: input.outpoint()?,
+                    script_sig: script::Builder::new().into_script(),
+                    witness: Witness::new(),
+                    sequence: match lock {
+                        Some(LeafTimelock::Relative(lock)) => relative_sequence(*lock),
+                        _ => LOCKTIME_AND_RBF,
+                    },
+                })
             })
-            .collect(),
-        output: vec![spend_to_receiver, spend_to_owner],
+            .collect::<Result<_>>()?,
+        output,
     };
 
-    // mock witness data structure
-    let mock_signature = bitcoin::taproot::Signature {
-        sig: Signature::from_slice(&[0; 64]).unwrap(),
-        hash_ty: TapSighashType::Default,
-    }
-    .to_vec();
-    let mut witness = Witness::new();
-    witness.push(mock_signature.clone());
-    match spent_type {
-        SpendType::MULSIG => {
-            witness.push(mock_signature);
-            witness.push(mulsig_script.clone());
-            witness.push(&control_block.serialize());
-        }
-        SpendType::ESCAPE(_) => {
-            witness.push(escape_script.clone());
-            witness.push(&control_block.serialize());
-        }
-        _ => {}
-    }
-    unsigned_tx
-        .input
-        .iter_mut()
-        .for_each(|v| v.witness = witness.clone());
-
-    let fee = calculate_fee(unsigned_tx.vsize(), fee_rate, 1.0);
-    println!("fee: {} sat", fee);
+    let witness_weight = input_policies
+        .iter()
+        .map(|policy| predict_satisfaction_weight(policy, spent_type.leaf()))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .sum();
+    let fee_amount = fee.amount(satisfied_weight(&unsigned_tx, witness_weight))?.to_sat();
 
-    if total_amount < amount + fee {
-        panic!("invalid transaction amount, transfer out only: {}", total_amount - fee);
+    if total_amount < amount + fee_amount {
+        return Err(SelfCustodyError::InsufficientFunds {
+            available: total_amount,
+            needed: amount + fee_amount,
+        });
     }
 
-    let owner_amount = total_amount - amount - fee;
-    let dust_value = addr_self.script_pubkey().dust_value().to_sat();
-    println!("owner_amount: {} sat", owner_amount);
+    let owner_amount = total_amount - amount - fee_amount;
+    let change_output = unsigned_tx.output.last_mut().expect("change output");
+    let dust_value = change_output.script_pubkey.dust_value().to_sat();
 
     if owner_amount < dust_value {
-        // remove second utxo
+        if sweep || unsigned_tx.output.len() == 1 {
+            // the change is the only output or the one swept to
+            return Err(SelfCustodyError::InsufficientFunds {
+                available: total_amount,
+                needed: amount + fee_amount + dust_value,
+            });
+        }
+        // remove the change output
         unsigned_tx.output.pop();
     } else {
         // update the txOut's amount
-        // the second utxo is for yourself.
-        unsigned_tx.output[1].value = Amount::from_sat(owner_amount);
+        // the last output is for yourself.
+        change_output.value = Amount::from_sat(owner_amount);
     }
+    // a change below dust is left to the fee
+    let paid: u64 = unsigned_tx.output.iter().map(|v| v.value.to_sat()).sum();
+    let weight = satisfied_weight(&unsigned_tx, witness_weight);
+    fee.check(Amount::from_sat(total_amount - paid), weight)?;
 
-    // build sign hash
-    let inputs = unsigned_tx.input.len();
-    let mut sighasher = SighashCache::new(&mut unsigned_tx);
     let prevouts: Vec<TxOut> = utxos
         .iter()
-        .map(|v| TxOut {
+        .zip(&input_policies)
+        .map(|(v, policy)| TxOut {
             value: Amount::from_sat(v.amount),
-            script_pubkey: addr_self.script_pubkey(),
+            script_pubkey: policy.script_pubkey(),
         })
         .collect();
 
-    let mut sig_hashs = Vec::<TapSighash>::new();
-    for i in 0..inputs {
-        let mock_script = ScriptBuf::new();
-        let (is_script, script) = match spent_type {
-            SpendType::ESCAPE(_) => (true, &escape_script),
-            SpendType::MULSIG => (true, &mulsig_script),
-            SpendType::KEY => (false, &mock_script),
-        };
-        let sig_hash: TapSighash = if is_script {
-            sighasher
-                .taproot_script_spend_signature_hash(
-                    i,
-                    &Prevouts::All(&prevouts),
-                    TapLeafHash::from_script(script, LeafVersion::TapScript),
-                    TapSighashType::Default,
-                )
-                .expect("failed to construct TapSighash")
-        } else {
-            sighasher
-                .taproot_key_spend_signature_hash(
-                    i,
-                    &Prevouts::All(&prevouts),
-                    TapSighashType::Default,
-                )
-                .expect("failed to construct TapSighash")
-        };
-        sig_hashs.push(sig_hash);
+    let mut psbt = Psbt::from_unsigned_tx(unsigned_tx)
+        .map_err(|e| SelfCustodyError::InvalidPsbt(e.to_string()))?;
+    for ((input, prevout), policy) in psbt.inputs.iter_mut().zip(prevouts).zip(input_policies) {
+        input.witness_utxo = Some(prevout);
+        input.tap_internal_key = Some(policy.internal_key());
+        input.tap_merkle_root = policy.merkle_root();
+        match spent_type.leaf() {
+            Some(leaf) => {
+                let script = policy.script(leaf)?.clone();
+                let leaf_hash = policy.leaf_hash(leaf)?;
+                for key in script_keys(&script) {
+                    input
+                        .tap_key_origins
+                        .insert(key, (vec![leaf_hash], KeySource::default()));
+                }
+                input
+                    .tap_scripts
+                    .insert(policy.control_block(leaf)?, (script, LeafVersion::TapScript));
+            }
+            None => {
+                input
+                    .tap_key_origins
+                    .insert(policy.internal_key(), (vec![], KeySource::default()));
+            }
+        }
+    }
+
+    Ok(psbt)
+}
+
+/// The policy of `utxo`, which must have the leaf of `spent_type` or a key path.
+fn spend_policy<'a>(
+    spent_type: SpendType,
+    utxo: &LocalUtxo,
+    policies: &'a [CustodyPolicy],
+) -> Result<&'a CustodyPolicy> {
+    let policy = input_policy(utxo, policies)?;
+    match spent_type.leaf() {
+        Some(leaf) if !policy.has_leaf(leaf) => Err(SelfCustodyError::MissingLeaf(leaf)),
+        None if !policy.has_key_path() => Err(SelfCustodyError::InvalidUtxo(format!(
+            "{}:{} has an unspendable internal key",
+            utxo.txid, utxo.vout
+        ))),
+        _ => Ok(policy),
+    }
+}
+
+/// Worst-case weight of the witness satisfying `leaf` of `policy`, or its key path without a
+/// leaf: every signature carries a sighash type byte and every key above a `multi_a` threshold
+/// an empty push. An input spending it weighs its non-witness part on top of this.
+pub fn predict_satisfaction_weight(policy: &CustodyPolicy, leaf: Option<Leaf>) -> Result<Weight> {
+    let items = match leaf {
+        Some(leaf) => {
+            let script = policy.script(leaf)?;
+            let keys = script_keys(script).len();
+            let required = required_signatures(script);
+            let mut items = vec![MAX_TAPROOT_SIGNATURE_SIZE; required.min(keys)];
+            items.resize(keys, 0);
+            items.push(script.len());
+            items.push(policy.control_block(leaf)?.size());
+            items
+        }
+        None => vec![MAX_TAPROOT_SIGNATURE_SIZE],
+    };
+    let weight = items
+        .iter()
+        .map(|len| VarInt::from(*len).size() + len)
+        .sum::<usize>()
+        + VarInt::from(items.len()).size();
+    Ok(Weight::from_wu(weight as u64))
+}
+
+/// Weight of `tx` once every input carries the witness of `witness_weight` in total.
+fn satisfied_weight(tx: &Transaction, witness_weight: Weight) -> Weight {
+    // segwit marker and flag
+    Weight::from_wu(tx.base_size() as u64 * 4 + 2) + witness_weight
+}
+
+/// The `nLockTime` of a spend through `spent_type`, zero unless a spent leaf has an `OP_CLTV`
+/// lock. Its lock must be in the domain of every `OP_CLTV` lock of the spent leaves, block
+/// heights or timestamps, and reach all of them.
+fn spend_lock_time(spent_type: SpendType, leaf_locks: &[Option<LeafTimelock>]) -> Result<LockTime> {
+    let absolute = leaf_locks
+        .iter()
+        .any(|lock| matches!(lock, Some(LeafTimelock::Absolute(_))));
+    let lock_time = match spent_type {
+        SpendType::ESCAPE(t) | SpendType::ENFORCE(t) if absolute => LockTime::from_consensus(t),
+        _ => LockTime::ZERO,
+    };
+    for lock in leaf_locks.iter().flatten() {
+        if let LeafTimelock::Absolute(lock) = lock {
+            if !lock.is_same_unit(lock_time) {
+                return Err(SelfCustodyError::InvalidTimelock(format!(
+                    "lock time {} and script lock {} are not both block heights or timestamps",
+                    lock_time, lock
+                )));
+            }
+            if !lock.is_implied_by(lock_time) {
+                return Err(SelfCustodyError::InvalidTimelock(format!(
+                    "lock time {} is before the script lock {}",
+                    lock_time, lock
+                )));
+            }
+        }
+    }
+    Ok(lock_time)
+}
+
+/// The tip of the chain a transaction is about to be broadcast on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChainTip {
+    pub height: u32,
+    /// Median time past of the tip, BIP-113 compares timestamp locks against it.
+    pub median_time_past: u32,
+}
+
+/// Fails with `LockTimeNotReached` when `tx` can't be mined in the block after `tip`, whose height
+/// must be above a height lock and whose median time past must be above a timestamp lock.
+pub fn check_lock_time(tx: &Transaction, tip: ChainTip) -> Result<()> {
+    if !tx.is_lock_time_enabled() {
+        return Ok(());
+    }
+    let reached = match tx.lock_time {
+        LockTime::Blocks(height) => height.to_consensus_u32() <= tip.height,
+        LockTime::Seconds(time) => time.to_consensus_u32() < tip.median_time_past,
+    };
+    if !reached {
+        return Err(SelfCustodyError::LockTimeNotReached {
+            lock_time: tx.lock_time,
+            height: tip.height,
+            median_time_past: tip.median_time_past,
+        });
+    }
+    Ok(())
+}
+
+/// Witness weight of a wallet input spending `script_pubkey`. Only p2wpkh and taproot key path
+/// inputs are supported, their PSBT only needs the witness utxo.
+fn wallet_input_weight(script_pubkey: &ScriptBuf) -> Result<u64> {
+    if script_pubkey.is_p2tr() {
+        // item count, signature push
+        Ok(1 + 1 + 64)
+    } else if script_pubkey.is_p2wpkh() {
+        // item count, signature push, compressed key push
+        Ok(1 + 1 + 72 + 1 + 33)
+    } else {
+        Err(SelfCustodyError::InvalidUtxo(format!(
+            "unsupported wallet input script {}",
+            script_pubkey
+        )))
+    }
+}
+
+/// Mapping credential transaction: funds the custody address of `policy` with `amount` from
+/// arbitrary wallet utxos and appends the mapping OP_RETURN. Every utxo must carry its
+/// `script_pubkey`, the change goes to `change` when above dust.
+pub fn build_mapping_tx(
+    policy: &CustodyPolicy,
+    utxos: Vec<LocalUtxo>,
+    amount: u64,
+    data: &MapOpReturnData,
+    fee: Fee,
+    change: Address,
+) -> Result<Psbt> {
+    let prevouts = utxos
+        .iter()
+        .map(|utxo| {
+            let script_pubkey =
+                utxo.script_pubkey()?
+                    .ok_or(SelfCustodyError::InvalidUtxo(format!(
+                        "{}:{} has no script_pubkey",
+                        utxo.txid, utxo.vout
+                    )))?;
+            Ok(TxOut {
+                value: Amount::from_sat(utxo.amount),
+                script_pubkey,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let total_amount: u64 = utxos.iter().map(|v| v.amount).sum();
+    let dust_value = policy.script_pubkey().dust_value().to_sat();
+    if amount < dust_value {
+        return Err(SelfCustodyError::InvalidUtxo(format!(
+            "mapping {} sat is below the dust value {} sat of the custody output",
+            amount, dust_value
+        )));
+    }
+
+    let mut unsigned_tx = Transaction {
+        version: transaction::Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: utxos
+            .iter()
+            .map(|input| {
+                Ok(TxIn {
+                    previous_output: input.outpoint()?,
+                    script_sig: ScriptBuf::new(),
+                    witness: Witness::new(),
+                    sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                })
+            })
+            .collect::<Result<_>>()?,
+        output: vec![
+            TxOut {
+                value: Amount::from_sat(amount),
+                script_pubkey: policy.script_pubkey(),
+            },
+            TxOut {
+                value: Amount::ZERO,
+                script_pubkey: data.script(),
+            },
+            TxOut {
+                value: Amount::from_sat(total_amount),
+                script_pubkey: change.script_pubkey(),
+            },
+        ],
+    };
+
+    // segwit marker and flag, then every input's witness
+    let mut weight = unsigned_tx.weight().to_wu() + 2;
+    for prevout in prevouts.iter() {
+        weight += wallet_input_weight(&prevout.script_pubkey)?;
+    }
+    let fee_amount = fee.amount(Weight::from_wu(weight))?.to_sat();
+
+    if total_amount < amount + fee_amount {
+        return Err(SelfCustodyError::InsufficientFunds {
+            available: total_amount,
+            needed: amount + fee_amount,
+        });
+    }
+    let change_amount = total_amount - amount - fee_amount;
+    if change_amount < change.script_pubkey().dust_value().to_sat() {
+        let change = unsigned_tx.output.pop().expect("change output");
+        weight -= change.weight().to_wu();
+    } else {
+        unsigned_tx.output[2].value = Amount::from_sat(change_amount);
+    }
+    let paid: u64 = unsigned_tx.output.iter().map(|v| v.value.to_sat()).sum();
+    fee.check(Amount::from_sat(total_amount - paid), Weight::from_wu(weight))?;
+
+    let mut psbt = Psbt::from_unsigned_tx(unsigned_tx)
+        .map_err(|e| SelfCustodyError::InvalidPsbt(e.to_string()))?;
+    for (input, prevout) in psbt.inputs.iter_mut().zip(prevouts) {
+        input.witness_utxo = Some(prevout);
     }
 
-    (unsigned_tx, sig_hashs, taproot_spend_info.merkle_root())
+    Ok(psbt)
 }
@@ -0,0 +1,404 @@
+use std::ops::Deref;
+
+use bitcoin::absolute::LockTime;
+use bitcoin::psbt::Psbt;
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::{Address, Amount, Network};
+
+use crate::builder::{
+    build_burn_tx, build_forced_withdrawal_tx, build_tx, Payment, SpendType,
+};
+use crate::credential::BurnOpReturnData;
+use crate::error::{Result, SelfCustodyError};
+use crate::fee::Fee;
+use crate::musig::aggregate_keys;
+use crate::policy::CustodyPolicy;
+use crate::script::{build_committee, build_enforce, build_escape, build_mulsig2};
+use crate::LocalUtxo;
+
+/// Six months of blocks at one block every ten minutes.
+pub const SIX_MONTHS_BLOCKS: u32 = 26_280;
+/// Six months of 182.5 days in seconds.
+pub const SIX_MONTHS_SECONDS: u32 = 15_768_000;
+
+/// The absolute timelocks of the channels of one deployment. The README requires
+/// `one-way escape > escape > forced withdrawal`, all in blocks or all in seconds, and the escape
+/// of a two-way channel six months after its forced withdrawal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChannelParams {
+    enforce_time: LockTime,
+    escape_time: LockTime,
+    one_way_escape_time: LockTime,
+}
+
+impl ChannelParams {
+    /// Validates the ordering of the locks, given as consensus `nLockTime` values. The escape lock
+    /// must be six months after the forced withdrawal lock.
+    pub fn new(enforce_time: u32, escape_time: u32, one_way_escape_time: u32) -> Result<Self> {
+        Self::check_two_way(enforce_time, escape_time)?;
+        let enforce = LockTime::from_consensus(enforce_time);
+        let escape = LockTime::from_consensus(escape_time);
+        let one_way_escape = LockTime::from_consensus(one_way_escape_time);
+        if !escape.is_same_unit(one_way_escape) {
+            return Err(SelfCustodyError::InvalidTimelock(format!(
+                "mixed block height and timestamp locks: {}, {}, {}",
+                enforce, escape, one_way_escape
+            )));
+        }
+        if one_way_escape_time <= escape_time {
+            return Err(SelfCustodyError::InvalidTimelock(format!(
+                "one-way escape lock {} is not after the escape lock {}",
+                one_way_escape, escape
+            )));
+        }
+
+        Ok(ChannelParams {
+            enforce_time: enforce,
+            escape_time: escape,
+            one_way_escape_time: one_way_escape,
+        })
+    }
+
+    /// Validates the locks of a two-way channel alone: the same unit, and the escape lock six
+    /// months after the forced withdrawal lock.
+    pub fn check_two_way(enforce_time: u32, escape_time: u32) -> Result<()> {
+        let enforce = LockTime::from_consensus(enforce_time);
+        let escape = LockTime::from_consensus(escape_time);
+        if !enforce.is_same_unit(escape) {
+            return Err(SelfCustodyError::InvalidTimelock(format!(
+                "mixed block height and timestamp locks: {}, {}",
+                enforce, escape
+            )));
+        }
+        if escape_time != Self::default_escape_time(enforce_time)? {
+            return Err(SelfCustodyError::InvalidTimelock(format!(
+                "escape lock {} is not six months after the forced withdrawal lock {}",
+                escape, enforce
+            )));
+        }
+        Ok(())
+    }
+
+    /// The escape lock derived from the forced withdrawal lock.
+    pub fn with_default_escape(enforce_time: u32, one_way_escape_time: u32) -> Result<Self> {
+        Self::new(
+            enforce_time,
+            Self::default_escape_time(enforce_time)?,
+            one_way_escape_time,
+        )
+    }
+
+    /// `enforce_time` plus six months in the unit of `enforce_time`.
+    pub fn default_escape_time(enforce_time: u32) -> Result<u32> {
+        let enforce = LockTime::from_consensus(enforce_time);
+        let delay = match enforce {
+            LockTime::Blocks(_) => SIX_MONTHS_BLOCKS,
+            LockTime::Seconds(_) => SIX_MONTHS_SECONDS,
+        };
+        enforce_time
+            .checked_add(delay)
+            .filter(|escape| LockTime::from_consensus(*escape).is_same_unit(enforce))
+            .ok_or(SelfCustodyError::InvalidTimelock(format!(
+                "no escape lock six months after {}",
+                enforce
+            )))
+    }
+
+    /// `escape_time` minus six months, the forced withdrawal lock of a two-way channel.
+    pub fn default_enforce_time(escape_time: u32) -> Result<u32> {
+        let escape = LockTime::from_consensus(escape_time);
+        let delay = match escape {
+            LockTime::Blocks(_) => SIX_MONTHS_BLOCKS,
+            LockTime::Seconds(_) => SIX_MONTHS_SECONDS,
+        };
+        escape_time
+            .checked_sub(delay)
+            .filter(|enforce| LockTime::from_consensus(*enforce).is_same_unit(escape))
+            .ok_or(SelfCustodyError::InvalidTimelock(format!(
+                "no forced withdrawal lock six months before {}",
+                escape
+            )))
+    }
+
+    pub fn enforce_time(&self) -> LockTime {
+        self.enforce_time
+    }
+
+    pub fn escape_time(&self) -> LockTime {
+        self.escape_time
+    }
+
+    pub fn one_way_escape_time(&self) -> LockTime {
+        self.one_way_escape_time
+    }
+}
+
+/// An exclusive two-way channel opened by a reviewed user, its owner. The owner and the
+/// committee co-sign, the committee forces a withdrawal after the enforce lock and the validator
+/// escapes after the escape lock. The internal key is the MuSig2 aggregate of the owner and
+/// committee keys.
+#[derive(Clone, Debug)]
+pub struct WhaleChannel {
+    owner: PublicKey,
+    committee: PublicKey,
+    validator: PublicKey,
+    params: ChannelParams,
+    policy: CustodyPolicy,
+}
+
+impl WhaleChannel {
+    pub fn new(
+        owner: PublicKey,
+        committee: PublicKey,
+        validator: PublicKey,
+        params: ChannelParams,
+        network: Network,
+    ) -> Result<Self> {
+        let internal_key = aggregate_keys(&[owner, committee])?;
+        let committee_xonly = committee.x_only_public_key().0;
+        let policy = CustodyPolicy::new(
+            internal_key,
+            build_mulsig2(committee_xonly, owner.x_only_public_key().0).into_script(),
+            build_enforce(params.enforce_time.to_consensus_u32(), committee_xonly)
+                .into_script(),
+            build_escape(
+                params.escape_time.to_consensus_u32(),
+                validator.x_only_public_key().0,
+            )
+            .into_script(),
+            network,
+        );
+        Ok(WhaleChannel {
+            owner,
+            committee,
+            validator,
+            params,
+            policy,
+        })
+    }
+
+    pub fn owner(&self) -> PublicKey {
+        self.owner
+    }
+
+    pub fn committee(&self) -> PublicKey {
+        self.committee
+    }
+
+    pub fn validator(&self) -> PublicKey {
+        self.validator
+    }
+
+    pub fn params(&self) -> ChannelParams {
+        self.params
+    }
+
+    pub fn policy(&self) -> &CustodyPolicy {
+        &self.policy
+    }
+
+    pub fn address(&self) -> Address {
+        self.policy.address()
+    }
+
+    /// The owner and committee co-signing, the forced withdrawal and the escape. The key path is
+    /// left out, it needs a MuSig2 session of the owner and the committee.
+    pub fn spend_paths(&self) -> Vec<SpendType> {
+        vec![
+            SpendType::MULSIG,
+            SpendType::ENFORCE(self.params.enforce_time.to_consensus_u32()),
+            SpendType::ESCAPE(self.params.escape_time.to_consensus_u32()),
+        ]
+    }
+
+    /// Burning credential co-signed by the owner and the committee.
+    pub fn burn(
+        &self,
+        utxos: Vec<LocalUtxo>,
+        fee: Fee,
+        mapper: Address,
+        amount: u64,
+        data: &BurnOpReturnData,
+    ) -> Result<Psbt> {
+        build_burn_tx(
+            std::slice::from_ref(&self.policy),
+            utxos,
+            fee,
+            mapper,
+            amount,
+            data,
+        )
+    }
+
+    /// Forced withdrawal of the committee into `one_way`, whose escape lock must be after the
+    /// escape lock of this channel.
+    pub fn forced_withdrawal(
+        &self,
+        utxos: Vec<LocalUtxo>,
+        fee: Fee,
+        one_way: &OneWayChannel,
+    ) -> Result<Psbt> {
+        if one_way.policy.network() != self.policy.network() {
+            return Err(SelfCustodyError::NetworkMismatch {
+                expected: self.policy.network(),
+                found: one_way.address().to_string(),
+            });
+        }
+        let one_way_escape = one_way.params.one_way_escape_time;
+        if !one_way_escape.is_same_unit(self.params.escape_time)
+            || one_way_escape.to_consensus_u32() <= self.params.escape_time.to_consensus_u32()
+        {
+            return Err(SelfCustodyError::InvalidTimelock(format!(
+                "one-way escape lock {} is not after the escape lock {}",
+                one_way_escape, self.params.escape_time
+            )));
+        }
+        build_forced_withdrawal_tx(
+            std::slice::from_ref(&self.policy),
+            utxos,
+            fee,
+            self.params.enforce_time.to_consensus_u32(),
+            &one_way.policy,
+        )
+    }
+
+    /// Escape hatch of the validator.
+    pub fn escape(
+        &self,
+        utxos: Vec<LocalUtxo>,
+        fee: Fee,
+        receiver: Address,
+        amount: u64,
+    ) -> Result<Psbt> {
+        build_tx(
+            SpendType::ESCAPE(self.params.escape_time.to_consensus_u32()),
+            std::slice::from_ref(&self.policy),
+            utxos,
+            fee,
+            &Payment::to(receiver, Amount::from_sat(amount)),
+        )
+    }
+}
+
+/// The whale channel opened exclusively by the Bool Network team, the owner is the team key.
+#[derive(Clone, Debug)]
+pub struct RetailChannel(WhaleChannel);
+
+impl RetailChannel {
+    pub fn new(
+        team: PublicKey,
+        committee: PublicKey,
+        validator: PublicKey,
+        params: ChannelParams,
+        network: Network,
+    ) -> Result<Self> {
+        WhaleChannel::new(team, committee, validator, params, network).map(RetailChannel)
+    }
+}
+
+impl Deref for RetailChannel {
+    type Target = WhaleChannel;
+
+    fn deref(&self) -> &WhaleChannel {
+        &self.0
+    }
+}
+
+/// The channel receiving forced withdrawals. The committee redeems WBTC back to BTC through its
+/// leaf and the validator escapes after the one-way escape lock.
+#[derive(Clone, Debug)]
+pub struct OneWayChannel {
+    committee: PublicKey,
+    validator: PublicKey,
+    params: ChannelParams,
+    policy: CustodyPolicy,
+}
+
+impl OneWayChannel {
+    /// The committee key is the internal key, it can spend through its leaf at any time anyway.
+    pub fn new(
+        committee: PublicKey,
+        validator: PublicKey,
+        params: ChannelParams,
+        network: Network,
+    ) -> Self {
+        let committee_xonly = committee.x_only_public_key().0;
+        let policy = CustodyPolicy::one_way(
+            committee_xonly,
+            build_committee(committee_xonly).into_script(),
+            build_escape(
+                params.one_way_escape_time.to_consensus_u32(),
+                validator.x_only_public_key().0,
+            )
+            .into_script(),
+            network,
+        );
+        OneWayChannel {
+            committee,
+            validator,
+            params,
+            policy,
+        }
+    }
+
+    pub fn committee(&self) -> PublicKey {
+        self.committee
+    }
+
+    pub fn validator(&self) -> PublicKey {
+        self.validator
+    }
+
+    pub fn params(&self) -> ChannelParams {
+        self.params
+    }
+
+    pub fn policy(&self) -> &CustodyPolicy {
+        &self.policy
+    }
+
+    pub fn address(&self) -> Address {
+        self.policy.address()
+    }
+
+    pub fn spend_paths(&self) -> Vec<SpendType> {
+        vec![
+            SpendType::COMMITTEE,
+            SpendType::ESCAPE(self.params.one_way_escape_time.to_consensus_u32()),
+        ]
+    }
+
+    /// Redemption by the committee alone.
+    pub fn redeem(
+        &self,
+        utxos: Vec<LocalUtxo>,
+        fee: Fee,
+        receiver: Address,
+        amount: u64,
+    ) -> Result<Psbt> {
+        build_tx(
+            SpendType::COMMITTEE,
+            std::slice::from_ref(&self.policy),
+            utxos,
+            fee,
+            &Payment::to(receiver, Amount::from_sat(amount)),
+        )
+    }
+
+    /// Escape hatch of the validator after the one-way escape lock.
+    pub fn escape(
+        &self,
+        utxos: Vec<LocalUtxo>,
+        fee: Fee,
+        receiver: Address,
+        amount: u64,
+    ) -> Result<Psbt> {
+        build_tx(
+            SpendType::ESCAPE(self.params.one_way_escape_time.to_consensus_u32()),
+            std::slice::from_ref(&self.policy),
+            utxos,
+            fee,
+            &Payment::to(receiver, Amount::from_sat(amount)),
+        )
+    }
+}
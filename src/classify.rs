@@ -0,0 +1,177 @@
+use bitcoin::taproot::TAPROOT_ANNEX_PREFIX;
+use bitcoin::{Script, Transaction, TxIn};
+
+use crate::credential::{BurnOpReturnData, MapOpReturnData};
+use crate::error::{Result, SelfCustodyError};
+use crate::policy::{CustodyPolicy, Leaf};
+
+/// The transaction types of the Bool Network system.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TxType {
+    Mapping,
+    Burning,
+    ForcedWithdrawal,
+    EscapeHatch,
+    /// The redemption of a one-way channel by the committee alone, a plain payment.
+    Redemption,
+    /// Neither spends from nor maps to the custody policy.
+    Unknown,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Classification {
+    pub tx_type: TxType,
+    pub map_data: Option<MapOpReturnData>,
+    pub burn_data: Option<BurnOpReturnData>,
+    /// The tapleaf of the policy spent by each input, `None` for any other spend.
+    pub inputs: Vec<Option<Leaf>>,
+    /// Rules of `tx_type` the transaction breaks, empty for a valid transaction.
+    pub violations: Vec<String>,
+}
+
+/// Recognizes which README transaction type `tx` is for `policy`. The type follows the leaf spent
+/// by the inputs, escape over enforce over mulsig over committee, and a transaction paying to the
+/// mapping script without spending from it is a mapping.
+pub fn classify_transaction(tx: &Transaction, policy: &CustodyPolicy) -> Classification {
+    let inputs: Vec<Option<Leaf>> = tx.input.iter().map(|v| spent_leaf(v, policy)).collect();
+    let spends = |leaf: Leaf| inputs.contains(&Some(leaf));
+    let tx_type = if spends(Leaf::Escape) {
+        TxType::EscapeHatch
+    } else if spends(Leaf::Enforce) {
+        TxType::ForcedWithdrawal
+    } else if spends(Leaf::Mulsig) {
+        TxType::Burning
+    } else if spends(Leaf::Committee) {
+        TxType::Redemption
+    } else if tx.output.iter().any(|v| v.script_pubkey == policy.script_pubkey()) {
+        TxType::Mapping
+    } else {
+        TxType::Unknown
+    };
+
+    let mut classification = Classification {
+        tx_type,
+        map_data: None,
+        burn_data: None,
+        inputs,
+        violations: vec![],
+    };
+    match tx_type {
+        TxType::Mapping => check_mapping(tx, &mut classification),
+        TxType::Burning => check_burning(tx, policy, &mut classification),
+        TxType::ForcedWithdrawal => check_forced_withdrawal(tx, &mut classification),
+        TxType::EscapeHatch => check_inputs(&[Leaf::Escape], &mut classification),
+        TxType::Redemption => check_inputs(&[Leaf::Committee], &mut classification),
+        TxType::Unknown => {}
+    }
+    classification
+}
+
+/// Checks a candidate forced withdrawal before the committee signs it: at least one input and a
+/// single output paying to one of the `recognized` mapping scripts, hence no OP_RETURN.
+pub fn validate_forced_withdrawal(tx: &Transaction, recognized: &[CustodyPolicy]) -> Result<()> {
+    if tx.input.is_empty() {
+        return Err(SelfCustodyError::PolicyViolation(
+            "a forced withdrawal spends at least one mapping output".to_string(),
+        ));
+    }
+    if tx.output.len() != 1 {
+        return Err(SelfCustodyError::PolicyViolation(format!(
+            "a forced withdrawal has exactly one output, found {}",
+            tx.output.len()
+        )));
+    }
+    let script_pubkey = &tx.output[0].script_pubkey;
+    if !recognized.iter().any(|policy| policy.script_pubkey() == *script_pubkey) {
+        return Err(SelfCustodyError::PolicyViolation(format!(
+            "output {} is not a recognized mapping script",
+            script_pubkey
+        )));
+    }
+    Ok(())
+}
+
+/// The leaf of `policy` whose script is revealed by the witness of `input`.
+pub fn spent_leaf(input: &TxIn, policy: &CustodyPolicy) -> Option<Leaf> {
+    let mut witness: Vec<&[u8]> = input.witness.iter().collect();
+    if witness.len() >= 2 && witness.last()?.first() == Some(&TAPROOT_ANNEX_PREFIX) {
+        witness.pop();
+    }
+    if witness.len() < 2 {
+        return None;
+    }
+    let script = Script::from_bytes(witness[witness.len() - 2]);
+    policy
+        .leaves()
+        .iter()
+        .find(|(_, leaf_script)| leaf_script.as_script() == script)
+        .map(|(leaf, _)| *leaf)
+}
+
+/// Every input must spend the mapping output through one of `leaves`.
+fn check_inputs(leaves: &[Leaf], classification: &mut Classification) {
+    for (i, spent) in classification.inputs.iter().enumerate() {
+        if !spent.is_some_and(|leaf| leaves.contains(&leaf)) {
+            classification
+                .violations
+                .push(format!("input {} does not spend one of the {:?} leaves", i, leaves));
+        }
+    }
+}
+
+fn check_mapping(tx: &Transaction, classification: &mut Classification) {
+    classification.map_data = tx
+        .output
+        .iter()
+        .filter(|v| v.script_pubkey.is_op_return())
+        .find_map(|v| MapOpReturnData::from_script(&v.script_pubkey).ok());
+    if classification.map_data.is_none() {
+        classification
+            .violations
+            .push("no OP_RETURN with the mapping data".to_string());
+    }
+}
+
+fn check_burning(tx: &Transaction, policy: &CustodyPolicy, classification: &mut Classification) {
+    check_inputs(&[Leaf::Mulsig], classification);
+    if tx.output.len() < 2 || tx.output.len() > 3 {
+        classification.violations.push(format!(
+            "a burning transaction has 2 or 3 outputs, found {}",
+            tx.output.len()
+        ));
+    }
+    match tx.output.get(1) {
+        Some(output) if output.script_pubkey.is_op_return() => {
+            match BurnOpReturnData::from_script(&output.script_pubkey) {
+                Ok(data) => classification.burn_data = Some(data),
+                Err(e) => classification.violations.push(format!("output 1: {}", e)),
+            }
+        }
+        _ => classification
+            .violations
+            .push("output 1 is not the burn OP_RETURN".to_string()),
+    }
+    if let Some(change) = tx.output.get(2) {
+        if change.script_pubkey != policy.script_pubkey() {
+            classification
+                .violations
+                .push("output 2 does not return the change to the mapping script".to_string());
+        }
+    }
+}
+
+fn check_forced_withdrawal(tx: &Transaction, classification: &mut Classification) {
+    check_inputs(&[Leaf::Enforce], classification);
+    if tx.output.len() != 1 {
+        classification.violations.push(format!(
+            "a forced withdrawal has exactly one output, found {}",
+            tx.output.len()
+        ));
+    }
+    // the output goes to a mapping script recognized by Bool Network, not necessarily this one
+    if tx.output.iter().any(|v| !v.script_pubkey.is_p2tr()) {
+        classification
+            .violations
+            .push("a forced withdrawal only pays to a taproot mapping script".to_string());
+    }
+}
@@ -1,56 +1,450 @@
-use bitcoin::key::Keypair;
-use bitcoin::secp256k1::PublicKey;
-use bitcoin::secp256k1::Secp256k1;
-use bitcoin::{Address, Network};
-use std::str::FromStr;
-
-use crate::aux::{combine_escape_transaction, sign_transaction};
-use crate::builder::{build_tx, SpendType};
-use crate::script::{build_escape, build_mulsig2};
+use bitcoin::consensus::encode;
+use bitcoin::psbt::Psbt;
+use bitcoin::relative;
+use bitcoin::script::Builder;
+use bitcoin::{Amount, FeeRate, Network, ScriptBuf, Transaction, TxOut, XOnlyPublicKey};
+use serde::Deserialize;
+
+use crate::aux::{
+    finalize_psbt, parse_address, parse_public_key, sign_psbt, verify_psbt_signatures,
+};
+use crate::builder::{
+    build_burn_tx, build_forced_withdrawal_tx, build_mapping_tx, build_tx, check_lock_time,
+    first_policy, select_burn_utxos, select_utxos, ChainTip, OutputOrdering, Payment, SpendType,
+};
+use crate::channel::ChannelParams;
+use crate::classify::validate_forced_withdrawal;
+use crate::credential::{BurnOpReturnData, MapOpReturnData};
+use crate::error::{Result, SelfCustodyError};
+use crate::fee::Fee;
+use crate::musig::aggregate_keys;
+use crate::policy::{CustodyPolicy, InternalKey, NumsKey};
+use crate::rbf::{bump_fee, BumpChange};
+use crate::script::{
+    build_committee, build_enforce, build_enforce_csv, build_escape, build_escape_csv,
+    build_mulsig2,
+};
+use crate::selection::{CoinSelection, CoinSelectionAlgorithm};
+use crate::verify::verify_transaction;
 use crate::LocalUtxo;
 
-pub fn build_mulsig_escape_command(
-    secret: &[u8],
-    time: u64,
-    committee: String,
-    multi_signer: String,
-    fee_rate: f64,
-    receiver: String,
-    receiver_amount: u64,
+/// How the enforce and escape leaves lock: an absolute `OP_CLTV` time, or an `OP_CSV` count of
+/// blocks or 512 second intervals since the deposit confirms.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LeafLock {
+    Absolute,
+    Blocks,
+    Intervals,
+}
+
+impl LeafLock {
+    /// The leaf script locking `key` until `time`.
+    fn script(
+        &self,
+        time: u32,
+        key: XOnlyPublicKey,
+        cltv: fn(u32, XOnlyPublicKey) -> Builder,
+        csv: fn(relative::LockTime, XOnlyPublicKey) -> Builder,
+    ) -> Result<ScriptBuf> {
+        if *self == LeafLock::Absolute {
+            return Ok(cltv(time, key).into_script());
+        }
+        let value = u16::try_from(time).map_err(|_| {
+            SelfCustodyError::InvalidTimelock(format!(
+                "relative lock {} is above {}",
+                time,
+                u16::MAX
+            ))
+        })?;
+        let lock = match self {
+            LeafLock::Blocks => relative::LockTime::Blocks(relative::Height::from(value)),
+            _ => relative::LockTime::Time(relative::Time::from_512_second_intervals(value)),
+        };
+        Ok(csv(lock, key).into_script())
+    }
+
+    /// Checks the forced withdrawal lock against the escape lock `escape_time` of a two-way
+    /// channel, through `ChannelParams` for absolute locks. Relative locks share the unit of
+    /// `self`, the escape only has to come after the forced withdrawal.
+    fn check(&self, enforce_time: u32, escape_time: u32) -> Result<()> {
+        if *self == LeafLock::Absolute {
+            return ChannelParams::check_two_way(enforce_time, escape_time);
+        }
+        if escape_time <= enforce_time {
+            return Err(SelfCustodyError::InvalidTimelock(format!(
+                "relative escape lock {} is not after the forced withdrawal lock {}",
+                escape_time, enforce_time
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// The mapping policy of a committee, a mapper (`multi_signer`) and a validator. The internal
+/// key is the MuSig2 aggregate of the committee and mapper keys, or the unspendable `nums` key.
+#[allow(clippy::too_many_arguments)]
+pub fn custody_policy(
+    committee: &str,
+    multi_signer: &str,
+    validator: &str,
+    enforce_time: u32,
+    escape_time: u32,
+    lock: LeafLock,
+    nums: Option<NumsKey>,
     network: Network,
-    utxos: Vec<LocalUtxo>
-) -> String {
-    let secp = Secp256k1::new();
-    let keypair = Keypair::from_seckey_slice(&secp, secret).unwrap();
-    let committee = PublicKey::from_str(&committee).unwrap();
-    let mulsigner = PublicKey::from_str(&multi_signer).unwrap();
-    let sum = committee.combine(&mulsigner).unwrap();
+) -> Result<CustodyPolicy> {
+    lock.check(enforce_time, escape_time)?;
+    let committee = parse_public_key(committee)?;
+    let mulsigner = parse_public_key(multi_signer)?;
+    let validator = parse_public_key(validator)?;
+    let internal_key = match nums {
+        Some(nums) => InternalKey::Nums(nums),
+        None => InternalKey::Key(aggregate_keys(&[committee, mulsigner])?),
+    };
 
     let mulsig2_script_builder = build_mulsig2(
         committee.x_only_public_key().0,
         mulsigner.x_only_public_key().0,
     );
-    let escape_script_builder = build_escape(time, keypair.x_only_public_key().0);
-    let receiver = Address::from_str(&receiver).unwrap().assume_checked();
+    let enforce_script = lock.script(
+        enforce_time,
+        committee.x_only_public_key().0,
+        build_enforce,
+        build_enforce_csv,
+    )?;
+    let escape_script =
+        lock.script(escape_time, validator.x_only_public_key().0, build_escape, build_escape_csv)?;
 
-    let (tx, sighashs, _) = build_tx(
-        SpendType::ESCAPE(time as u32),
-        sum.x_only_public_key().0,
+    Ok(CustodyPolicy::new(
+        internal_key,
         mulsig2_script_builder.into_script(),
-        escape_script_builder.into_script(),
+        enforce_script,
+        escape_script,
         network,
-        utxos,
-        fee_rate,
-        receiver,
-        receiver_amount,
-    );
+    ))
+}
+
+/// The one-way channel policy of a committee and a validator. The committee can spend through
+/// its leaf at any time, so its key is also the internal key. With the forced withdrawal lock
+/// `enforce_time` of the two-way channels, the escape lock is checked against theirs.
+pub fn one_way_policy(
+    committee: &str,
+    validator: &str,
+    escape_time: u32,
+    enforce_time: Option<u32>,
+    lock: LeafLock,
+    network: Network,
+) -> Result<CustodyPolicy> {
+    match enforce_time {
+        Some(enforce_time) if lock == LeafLock::Absolute => {
+            ChannelParams::with_default_escape(enforce_time, escape_time)?;
+        }
+        Some(enforce_time) => lock.check(enforce_time, escape_time)?,
+        None => {}
+    }
+    let committee = parse_public_key(committee)?.x_only_public_key().0;
+    let validator = parse_public_key(validator)?.x_only_public_key().0;
+
+    Ok(CustodyPolicy::one_way(
+        committee,
+        build_committee(committee).into_script(),
+        lock.script(escape_time, validator, build_escape, build_escape_csv)?,
+        network,
+    ))
+}
+
+/// The NUMS internal key `H + rG` of the hex randomizer `r`, or `H` without one.
+pub fn parse_nums_key(randomizer: Option<&str>) -> Result<NumsKey> {
+    let randomizer = randomizer
+        .map(|v| {
+            let bytes = hex::decode(v.trim_start_matches("0x"))
+                .map_err(|e| SelfCustodyError::InvalidKey(e.to_string()))?;
+            <[u8; 32]>::try_from(bytes).map_err(|_| {
+                SelfCustodyError::InvalidKey("the nums randomizer is 32 bytes".to_string())
+            })
+        })
+        .transpose()?;
+    NumsKey::new(randomizer)
+}
+
+/// A rate in sat/vB with up to three decimals, rounded up to the next sat/kwu.
+pub fn parse_fee_rate(rate: &str) -> Result<FeeRate> {
+    let invalid = || SelfCustodyError::InvalidFeeRate(format!("{} is not a sat/vB rate", rate));
+    let (sat, decimals) = rate.split_once('.').unwrap_or((rate, ""));
+    if decimals.len() > 3 || !decimals.bytes().all(|v| v.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    let sat: u64 = sat.parse().map_err(|_| invalid())?;
+    let millisat: u64 = format!("{:0<3}", decimals).parse().map_err(|_| invalid())?;
+    // 1 sat/vB is 250 sat/kwu
+    sat.checked_mul(1000)
+        .and_then(|v| v.checked_add(millisat))
+        .map(|v| FeeRate::from_sat_per_kwu(v.div_ceil(4)))
+        .ok_or_else(invalid)
+}
+
+/// The fee of `rate` in sat/vB, or the `absolute` fee in sat, with the optional caps.
+pub fn parse_fee(
+    rate: &str,
+    absolute: Option<u64>,
+    max_fee: Option<u64>,
+    max_fee_rate: Option<&str>,
+) -> Result<Fee> {
+    let mut fee = match absolute {
+        Some(fee) => Fee::absolute(Amount::from_sat(fee)),
+        None => Fee::from_rate(parse_fee_rate(rate)?),
+    };
+    if let Some(max_fee) = max_fee {
+        fee = fee.with_max_fee(Amount::from_sat(max_fee));
+    }
+    if let Some(max_fee_rate) = max_fee_rate {
+        fee = fee.with_max_fee_rate(parse_fee_rate(max_fee_rate)?);
+    }
+    Ok(fee)
+}
+
+pub fn parse_utxos(utxos: &str) -> Result<Vec<LocalUtxo>> {
+    serde_json::from_str(utxos).map_err(|e| SelfCustodyError::InvalidUtxo(e.to_string()))
+}
+
+pub fn address_command(policy: &CustodyPolicy) -> String {
+    policy.address().to_string()
+}
+
+/// The payment of `recipients`, addresses with their amount. With `sweep` the last recipient
+/// takes what is left and its amount is ignored.
+pub fn parse_payment(
+    recipients: &[(String, u64)],
+    change: Option<&str>,
+    sweep: bool,
+    ordering: OutputOrdering,
+    network: Network,
+) -> Result<Payment> {
+    let recipients = recipients
+        .iter()
+        .map(|(address, amount)| Ok((parse_address(address, network)?, Amount::from_sat(*amount))))
+        .collect::<Result<Vec<_>>>()?;
+    let mut payment = Payment::new(recipients).with_ordering(ordering);
+    if sweep {
+        payment = payment.with_sweep();
+    }
+    if let Some(change) = change {
+        payment = payment.with_change(parse_address(change, network)?);
+    }
+    Ok(payment)
+}
 
-    // sign by project party
-    let sig1 = sign_transaction(sighashs.clone(), secret);
+/// Builds the unsigned PSBT of `spent_type` for `payment`, hex encoded, spending the utxos
+/// selected with `fallback`, returned along, or all of them for a sweep. With a `tip`, a
+/// transaction that can't be mined in the next block is an error.
+pub fn build_command(
+    policies: &[CustodyPolicy],
+    spent_type: SpendType,
+    fee: Fee,
+    payment: &Payment,
+    utxos: Vec<LocalUtxo>,
+    fallback: CoinSelectionAlgorithm,
+    tip: Option<ChainTip>,
+) -> Result<(String, Option<CoinSelection>)> {
+    let selection = if payment.is_sweep() {
+        None
+    } else {
+        Some(select_utxos(spent_type, policies, utxos.clone(), fee, payment, fallback)?)
+    };
+    let utxos = selection.as_ref().map_or(utxos, |v| v.selected.clone());
+    let psbt = build_tx(spent_type, policies, utxos, fee, payment)?;
+    if let Some(tip) = tip {
+        check_lock_time(&psbt.unsigned_tx, tip)?;
+    }
+    Ok((psbt.serialize_hex(), selection))
+}
+
+/// Builds the unsigned forced withdrawal PSBT, hex encoded, moving every utxo through the enforce
+/// leaf to the one-way channel policy `destination`. The transaction is checked against the
+/// forced withdrawal rules before it is returned, and against `tip` like `build_command`.
+pub fn withdraw_command(
+    policies: &[CustodyPolicy],
+    enforce_time: u32,
+    fee: Fee,
+    destination: &CustodyPolicy,
+    utxos: Vec<LocalUtxo>,
+    tip: Option<ChainTip>,
+) -> Result<String> {
+    let psbt = build_forced_withdrawal_tx(policies, utxos, fee, enforce_time, destination)?;
+    validate_forced_withdrawal(&psbt.unsigned_tx, std::slice::from_ref(destination))?;
+    if let Some(tip) = tip {
+        check_lock_time(&psbt.unsigned_tx, tip)?;
+    }
+    Ok(psbt.serialize_hex())
+}
+
+/// Builds the unsigned mapping credential PSBT, hex encoded. `receiver` is the EVM address on
+/// chain `dst_chain` and `utxos` are wallet utxos carrying their `script_pubkey`.
+pub fn map_command(
+    policy: &CustodyPolicy,
+    fee: Fee,
+    amount: u64,
+    dst_chain: u32,
+    receiver: &str,
+    change: String,
+    utxos: Vec<LocalUtxo>,
+) -> Result<String> {
+    let data = MapOpReturnData::evm(dst_chain, receiver)?;
+    let change = parse_address(&change, policy.network())?;
+
+    Ok(build_mapping_tx(policy, utxos, amount, &data, fee, change)?.serialize_hex())
+}
+
+/// Builds the unsigned burning credential PSBT, hex encoded, spending the utxos selected with
+/// `fallback`, returned along. `uid` is the hex burn identifier, the change goes back to the
+/// first policy.
+pub fn burn_command(
+    policies: &[CustodyPolicy],
+    fee: Fee,
+    mapper: String,
+    amount: u64,
+    uid: &str,
+    utxos: Vec<LocalUtxo>,
+    fallback: CoinSelectionAlgorithm,
+) -> Result<(String, CoinSelection)> {
+    let network = first_policy(policies)?.network();
+    let mapper = parse_address(&mapper, network)?;
+    let uid = hex::decode(uid.trim_start_matches("0x"))
+        .map_err(|e| SelfCustodyError::InvalidCredential(e.to_string()))?;
+    let data = BurnOpReturnData::new(uid)?;
+
+    let selection = select_burn_utxos(policies, utxos, fee, &mapper, amount, &data, fallback)?;
+    let selected = selection.selected.clone();
+    let psbt = build_burn_tx(policies, selected, fee, mapper, amount, &data)?;
+    Ok((psbt.serialize_hex(), selection))
+}
+
+pub fn sign_command(psbt: &str, secret: &str) -> Result<String> {
+    let secret = hex::decode(secret).map_err(|e| SelfCustodyError::InvalidKey(e.to_string()))?;
+    let mut psbt = decode_psbt(psbt)?;
+    sign_psbt(&mut psbt, &secret)?;
+    Ok(psbt.serialize_hex())
+}
+
+/// Merges the signatures of PSBTs signed on separate machines.
+pub fn combine_command(psbts: &[String]) -> Result<String> {
+    let mut psbts = psbts.iter().map(|v| decode_psbt(v));
+    let mut combined = psbts
+        .next()
+        .ok_or(SelfCustodyError::InvalidPsbt("at least one psbt is needed".to_string()))??;
+    for psbt in psbts {
+        combined
+            .combine(psbt?)
+            .map_err(|e| SelfCustodyError::InvalidPsbt(e.to_string()))?;
+    }
+    Ok(combined.serialize_hex())
+}
+
+/// Finalizes the PSBT and returns the transaction hex ready to broadcast.
+pub fn finalize_command(psbt: &str) -> Result<String> {
+    Ok(encode::serialize_hex(&finalize_psbt(decode_psbt(psbt)?)?))
+}
 
-    // combine the transaction.
+/// Pretty prints either a PSBT or a raw transaction.
+pub fn decode_command(data: &str) -> Result<String> {
+    let bytes =
+        hex::decode(data.trim()).map_err(|e| SelfCustodyError::InvalidPsbt(e.to_string()))?;
+    match Psbt::deserialize(&bytes) {
+        Ok(psbt) => Ok(format!("{:#?}", psbt)),
+        Err(_) => {
+            let tx: Transaction = encode::deserialize(&bytes)
+                .map_err(|e| SelfCustodyError::InvalidTransaction(e.to_string()))?;
+            Ok(format!("{:#?}", tx))
+        }
+    }
+}
+
+/// Checks the signatures collected so far in the PSBT.
+pub fn verify_command(psbt: &str) -> Result<String> {
+    let psbt = decode_psbt(psbt)?;
+    Ok(verify_psbt_signatures(&psbt)?
+        .into_iter()
+        .map(|(input, key, valid)| {
+            format!("input {} key {}: {}", input, key, if valid { "ok" } else { "invalid" })
+        })
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+/// Runs a signed transaction through script verification. `prevouts` is the json list of the
+/// spent outputs in input order, e.g. '[{"amount":10000000,"script_pubkey":"5120..."}]'.
+pub fn verify_tx_command(tx: &str, prevouts: &str) -> Result<String> {
+    let tx = decode_tx(tx)?;
+    verify_transaction(&tx, &parse_prevouts(prevouts)?)?;
+    Ok(format!("transaction {} is valid", tx.txid()))
+}
+
+/// Builds the unsigned BIP-125 replacement of the signed transaction `tx`, hex encoded, see
+/// `bump_fee`. `prevouts` are in the format of `verify_tx_command`.
+pub fn bump_command(
+    policies: &[CustodyPolicy],
+    tx: &str,
+    prevouts: &str,
+    fee: Fee,
+    change: &BumpChange,
+    utxos: Vec<LocalUtxo>,
+) -> Result<String> {
+    let tx = decode_tx(tx)?;
+    let psbt = bump_fee(&tx, &parse_prevouts(prevouts)?, fee, policies, change, utxos)?;
+    Ok(psbt.serialize_hex())
+}
+
+/// The change of a replacement: output `index` of the original, swept with `sweep`, or a new
+/// output to `address` when the original has none.
+pub fn parse_bump_change(
+    index: Option<usize>,
+    sweep: bool,
+    address: Option<&str>,
+    network: Network,
+) -> Result<BumpChange> {
+    match (index, address) {
+        (Some(index), None) if sweep => Ok(BumpChange::Sweep(index)),
+        (Some(index), None) => Ok(BumpChange::Output(index)),
+        (None, Some(address)) if !sweep => {
+            Ok(BumpChange::New(parse_address(address, network)?.script_pubkey()))
+        }
+        _ => Err(SelfCustodyError::InvalidAddress(
+            "either a change output, swept or not, or a new change address".to_string(),
+        )),
+    }
+}
+
+fn decode_tx(tx: &str) -> Result<Transaction> {
+    let bytes =
+        hex::decode(tx.trim()).map_err(|e| SelfCustodyError::InvalidTransaction(e.to_string()))?;
+    encode::deserialize(&bytes).map_err(|e| SelfCustodyError::InvalidTransaction(e.to_string()))
+}
+
+fn parse_prevouts(prevouts: &str) -> Result<Vec<TxOut>> {
+    let prevouts: Vec<Prevout> = serde_json::from_str(prevouts)
+        .map_err(|e| SelfCustodyError::InvalidUtxo(e.to_string()))?;
+    prevouts
+        .into_iter()
+        .map(|v| {
+            Ok(TxOut {
+                value: Amount::from_sat(v.amount),
+                script_pubkey: ScriptBuf::from_hex(&v.script_pubkey)
+                    .map_err(|e| SelfCustodyError::InvalidUtxo(e.to_string()))?,
+            })
+        })
+        .collect()
+}
+
+#[derive(Deserialize)]
+struct Prevout {
+    amount: u64,
+    script_pubkey: String,
+}
 
-    combine_escape_transaction(tx, sig1)
+fn decode_psbt(psbt: &str) -> Result<Psbt> {
+    let bytes =
+        hex::decode(psbt.trim()).map_err(|e| SelfCustodyError::InvalidPsbt(e.to_string()))?;
+    Psbt::deserialize(&bytes).map_err(|e| SelfCustodyError::InvalidPsbt(e.to_string()))
 }
 
 #[cfg(test)]
@@ -0,0 +1,149 @@
+use bitcoin::opcodes::all::OP_RETURN;
+use bitcoin::script::{Builder, Instruction, PushBytesBuf, Script};
+use bitcoin::ScriptBuf;
+
+use crate::error::{Result, SelfCustodyError};
+
+/// Largest OP_RETURN payload relayed by default.
+pub const MAX_OP_RETURN_DATA: usize = 80;
+pub const EVM_ADDRESS_LEN: usize = 20;
+
+/// Payload of the mapping credential OP_RETURN: the 4 byte big endian chain id of the target
+/// chain, then the receiver length and bytes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MapOpReturnData {
+    dst_chain: u32,
+    receiver: Vec<u8>,
+}
+
+impl MapOpReturnData {
+    pub fn new(dst_chain: u32, receiver: Vec<u8>) -> Result<Self> {
+        if receiver.is_empty() || 5 + receiver.len() > MAX_OP_RETURN_DATA {
+            return Err(SelfCustodyError::InvalidCredential(format!(
+                "receiver must be 1 to {} bytes, got {}",
+                MAX_OP_RETURN_DATA - 5,
+                receiver.len()
+            )));
+        }
+        Ok(MapOpReturnData {
+            dst_chain,
+            receiver,
+        })
+    }
+
+    /// A receiver on an EVM chain, `receiver` is the hex address with or without `0x`.
+    pub fn evm(chain_id: u32, receiver: &str) -> Result<Self> {
+        let receiver = hex::decode(receiver.trim_start_matches("0x"))
+            .map_err(|e| SelfCustodyError::InvalidCredential(e.to_string()))?;
+        if receiver.len() != EVM_ADDRESS_LEN {
+            return Err(SelfCustodyError::InvalidCredential(format!(
+                "evm address must be {} bytes, got {}",
+                EVM_ADDRESS_LEN,
+                receiver.len()
+            )));
+        }
+        Self::new(chain_id, receiver)
+    }
+
+    pub fn dst_chain(&self) -> u32 {
+        self.dst_chain
+    }
+
+    pub fn receiver(&self) -> &[u8] {
+        &self.receiver
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.dst_chain.to_be_bytes().to_vec();
+        bytes.push(self.receiver.len() as u8);
+        bytes.extend_from_slice(&self.receiver);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < 5 || bytes.len() != 5 + bytes[4] as usize {
+            return Err(SelfCustodyError::InvalidCredential(format!(
+                "malformed mapping data of {} bytes",
+                bytes.len()
+            )));
+        }
+        let dst_chain = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        Self::new(dst_chain, bytes[5..].to_vec())
+    }
+
+    pub fn script(&self) -> ScriptBuf {
+        op_return_script(self.to_bytes())
+    }
+
+    pub fn from_script(script: &Script) -> Result<Self> {
+        Self::from_bytes(&op_return_data(script)?)
+    }
+}
+
+/// Payload of the burning credential OP_RETURN, the unique identifier of the burn.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BurnOpReturnData {
+    uid: Vec<u8>,
+}
+
+impl BurnOpReturnData {
+    pub fn new(uid: Vec<u8>) -> Result<Self> {
+        if uid.is_empty() || uid.len() > MAX_OP_RETURN_DATA {
+            return Err(SelfCustodyError::InvalidCredential(format!(
+                "uid must be 1 to {} bytes, got {}",
+                MAX_OP_RETURN_DATA,
+                uid.len()
+            )));
+        }
+        Ok(BurnOpReturnData { uid })
+    }
+
+    pub fn uid(&self) -> &[u8] {
+        &self.uid
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.uid.clone()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Self::new(bytes.to_vec())
+    }
+
+    pub fn script(&self) -> ScriptBuf {
+        op_return_script(self.to_bytes())
+    }
+
+    pub fn from_script(script: &Script) -> Result<Self> {
+        Self::from_bytes(&op_return_data(script)?)
+    }
+}
+
+/// `OP_RETURN <data>`. The README writes the push as `0x47`, the push opcode here follows the
+/// payload length so the script is a minimal push of any valid payload.
+pub(crate) fn op_return_script(data: Vec<u8>) -> ScriptBuf {
+    let data = PushBytesBuf::try_from(data).expect("payload is at most 80 bytes");
+    Builder::new()
+        .push_opcode(OP_RETURN)
+        .push_slice(data)
+        .into_script()
+}
+
+pub(crate) fn op_return_data(script: &Script) -> Result<Vec<u8>> {
+    let mut instructions = script.instructions();
+    let data = match (
+        instructions.next(),
+        instructions.next(),
+        instructions.next(),
+    ) {
+        (Some(Ok(Instruction::Op(OP_RETURN))), Some(Ok(Instruction::PushBytes(data))), None) => {
+            data.as_bytes().to_vec()
+        }
+        _ => {
+            return Err(SelfCustodyError::InvalidCredential(
+                "not an OP_RETURN with a single push".to_string(),
+            ))
+        }
+    };
+    Ok(data)
+}
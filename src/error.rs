@@ -0,0 +1,124 @@
+use std::fmt;
+
+use bitcoin::absolute::LockTime;
+use bitcoin::Network;
+
+use crate::policy::Leaf;
+
+pub type Result<T> = std::result::Result<T, SelfCustodyError>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SelfCustodyError {
+    InvalidKey(String),
+    InvalidAddress(String),
+    NetworkMismatch { expected: Network, found: String },
+    InvalidUtxo(String),
+    InvalidFeeRate(String),
+    InvalidCredential(String),
+    InvalidTimelock(String),
+    InsufficientFunds { available: u64, needed: u64 },
+    /// The fee is above the maximum fee or fee rate set for the build.
+    FeeTooHigh(String),
+    /// The transaction can't be mined before a later block than the one after the tip.
+    LockTimeNotReached { lock_time: LockTime, height: u32, median_time_past: u32 },
+    /// The leaf of `input` needs `expected` signatures but only `found` were collected.
+    SignatureCountMismatch { input: usize, expected: usize, found: usize },
+    /// `input` has neither a key path signature nor a leaf script to satisfy.
+    MissingWitnessTemplate(usize),
+    /// The custody policy has no script leaf for the spend path.
+    MissingLeaf(Leaf),
+    /// A MuSig2 session can't go on: bad nonce, foreign key or invalid partial signature.
+    MuSig(String),
+    MissingWitnessUtxo(usize),
+    InvalidPsbt(String),
+    InvalidTransaction(String),
+    Sighash(String),
+    ScriptVerification { input: usize, reason: String },
+    /// The transaction breaks a rule of its Bool Network transaction type.
+    PolicyViolation(String),
+}
+
+impl SelfCustodyError {
+    /// Process exit code of the CLI, grouped by the kind of mistake.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            SelfCustodyError::InvalidKey(_) => 2,
+            SelfCustodyError::InvalidAddress(_) | SelfCustodyError::NetworkMismatch { .. } => 3,
+            SelfCustodyError::InvalidUtxo(_)
+            | SelfCustodyError::InvalidFeeRate(_)
+            | SelfCustodyError::InvalidCredential(_)
+            | SelfCustodyError::InvalidTimelock(_) => 4,
+            SelfCustodyError::InsufficientFunds { .. } | SelfCustodyError::FeeTooHigh(_) => 5,
+            SelfCustodyError::SignatureCountMismatch { .. }
+            | SelfCustodyError::MissingWitnessTemplate(_)
+            | SelfCustodyError::MissingLeaf(_)
+            | SelfCustodyError::MuSig(_) => 6,
+            SelfCustodyError::MissingWitnessUtxo(_)
+            | SelfCustodyError::InvalidPsbt(_)
+            | SelfCustodyError::InvalidTransaction(_)
+            | SelfCustodyError::Sighash(_) => 7,
+            SelfCustodyError::ScriptVerification { .. } => 8,
+            SelfCustodyError::PolicyViolation(_) => 9,
+            SelfCustodyError::LockTimeNotReached { .. } => 10,
+        }
+    }
+}
+
+impl fmt::Display for SelfCustodyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SelfCustodyError::InvalidKey(e) => write!(f, "invalid key: {}", e),
+            SelfCustodyError::InvalidAddress(e) => write!(f, "invalid address: {}", e),
+            SelfCustodyError::NetworkMismatch { expected, found } => {
+                write!(f, "address {} is not valid on {}", found, expected)
+            }
+            SelfCustodyError::InvalidUtxo(e) => write!(f, "invalid utxo: {}", e),
+            SelfCustodyError::InvalidFeeRate(e) => write!(f, "invalid fee rate: {}", e),
+            SelfCustodyError::InvalidCredential(e) => write!(f, "invalid credential: {}", e),
+            SelfCustodyError::InvalidTimelock(e) => write!(f, "invalid timelock: {}", e),
+            SelfCustodyError::InsufficientFunds { available, needed } => write!(
+                f,
+                "insufficient funds: {} sat available, {} sat needed",
+                available, needed
+            ),
+            SelfCustodyError::FeeTooHigh(e) => write!(f, "fee too high: {}", e),
+            SelfCustodyError::LockTimeNotReached {
+                lock_time,
+                height,
+                median_time_past,
+            } => write!(
+                f,
+                "lock time {} is not reached at height {} with median time past {}",
+                lock_time, height, median_time_past
+            ),
+            SelfCustodyError::SignatureCountMismatch {
+                input,
+                expected,
+                found,
+            } => write!(
+                f,
+                "input {} needs {} signatures, found {}",
+                input, expected, found
+            ),
+            SelfCustodyError::MissingWitnessTemplate(input) => {
+                write!(f, "input {} has no spend path to satisfy", input)
+            }
+            SelfCustodyError::MissingLeaf(leaf) => {
+                write!(f, "the custody policy has no {:?} leaf", leaf)
+            }
+            SelfCustodyError::MuSig(e) => write!(f, "musig2 session failed: {}", e),
+            SelfCustodyError::MissingWitnessUtxo(input) => {
+                write!(f, "input {} has no witness utxo", input)
+            }
+            SelfCustodyError::InvalidPsbt(e) => write!(f, "invalid psbt: {}", e),
+            SelfCustodyError::InvalidTransaction(e) => write!(f, "invalid transaction: {}", e),
+            SelfCustodyError::Sighash(e) => write!(f, "failed to compute sighash: {}", e),
+            SelfCustodyError::ScriptVerification { input, reason } => {
+                write!(f, "input {} failed script verification: {}", input, reason)
+            }
+            SelfCustodyError::PolicyViolation(e) => write!(f, "policy violation: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SelfCustodyError {}
@@ -0,0 +1,122 @@
+//! The fee of the transactions built, in integer satoshis.
+
+use bitcoin::{Amount, FeeRate, Weight};
+
+use crate::error::{Result, SelfCustodyError};
+
+/// The rate a replacement pays on top of the fee of the original, 1 sat/vB as nodes default to.
+pub const INCREMENTAL_RELAY_FEE: FeeRate = FeeRate::from_sat_per_kwu(250);
+
+/// The fee of a transaction: a rate or an absolute amount, with optional caps aborting the build
+/// of a transaction paying more.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Fee {
+    rate: FeeRate,
+    absolute: Option<Amount>,
+    max_fee: Option<Amount>,
+    max_fee_rate: Option<FeeRate>,
+    /// The fee and weight of the transaction replaced.
+    replaced: Option<(Amount, Weight)>,
+}
+
+impl Fee {
+    pub fn from_rate(rate: FeeRate) -> Self {
+        Fee {
+            rate,
+            absolute: None,
+            max_fee: None,
+            max_fee_rate: None,
+            replaced: None,
+        }
+    }
+
+    pub fn from_sat_per_vb(sat_vb: u64) -> Result<Self> {
+        FeeRate::from_sat_per_vb(sat_vb)
+            .map(Self::from_rate)
+            .ok_or(SelfCustodyError::InvalidFeeRate(format!("{} sat/vB overflows", sat_vb)))
+    }
+
+    /// A fixed fee whatever the size of the transaction.
+    pub fn absolute(fee: Amount) -> Self {
+        Fee {
+            absolute: Some(fee),
+            ..Self::from_rate(FeeRate::ZERO)
+        }
+    }
+
+    pub fn with_max_fee(mut self, max_fee: Amount) -> Self {
+        self.max_fee = Some(max_fee);
+        self
+    }
+
+    pub fn with_max_fee_rate(mut self, max_fee_rate: FeeRate) -> Self {
+        self.max_fee_rate = Some(max_fee_rate);
+        self
+    }
+
+    /// The fee of a BIP-125 replacement of a transaction paying `fee` for `weight`.
+    pub fn replacing(mut self, fee: Amount, weight: Weight) -> Self {
+        self.replaced = Some((fee, weight));
+        self
+    }
+
+    /// The fee of a transaction of `weight`: the absolute fee, or the rate times the weight
+    /// rounded up to whole vbytes, as nodes count it, rounded up to the next satoshi. A
+    /// replacement pays at least the fee of the original plus its own relay, at a higher rate.
+    pub fn amount(&self, weight: Weight) -> Result<Amount> {
+        let fee = match self.absolute {
+            Some(fee) => fee,
+            None => rate_fee(self.rate, weight)?,
+        };
+        let Some((original, original_weight)) = self.replaced else {
+            return Ok(fee);
+        };
+        let relay = original
+            .checked_add(rate_fee(INCREMENTAL_RELAY_FEE, weight)?)
+            .ok_or(SelfCustodyError::InvalidFeeRate(format!(
+                "{} sat overflows",
+                original.to_sat()
+            )))?;
+        let above_rate = original.to_sat() as u128 * weight.to_wu() as u128
+            / original_weight.to_wu().max(1) as u128
+            + 1;
+        Ok(fee.max(relay).max(Amount::from_sat(above_rate as u64)))
+    }
+
+    /// Fails with `FeeTooHigh` when `fee` for a transaction of `weight` is above a cap.
+    pub fn check(&self, fee: Amount, weight: Weight) -> Result<()> {
+        if let Some(max_fee) = self.max_fee {
+            if fee > max_fee {
+                return Err(SelfCustodyError::FeeTooHigh(format!(
+                    "fee {} sat is above the maximum {} sat",
+                    fee.to_sat(),
+                    max_fee.to_sat()
+                )));
+            }
+        }
+        if let Some(max_fee_rate) = self.max_fee_rate {
+            if fee > rate_fee(max_fee_rate, weight)? {
+                return Err(SelfCustodyError::FeeTooHigh(format!(
+                    "fee {} sat for {:#} is above the maximum rate {} sat/kwu",
+                    fee.to_sat(),
+                    weight,
+                    max_fee_rate.to_sat_per_kwu()
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `rate` times `weight` in whole vbytes, rounded up.
+fn rate_fee(rate: FeeRate, weight: Weight) -> Result<Amount> {
+    let vbyte_weight = weight.to_wu().div_ceil(4) * 4;
+    rate.to_sat_per_kwu()
+        .checked_mul(vbyte_weight)
+        .map(|v| Amount::from_sat(v.div_ceil(1000)))
+        .ok_or(SelfCustodyError::InvalidFeeRate(format!(
+            "{} sat/kwu for {:#} overflows",
+            rate.to_sat_per_kwu(),
+            weight
+        )))
+}
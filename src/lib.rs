@@ -0,0 +1,54 @@
+pub mod aux;
+pub mod builder;
+pub mod channel;
+pub mod classify;
+pub mod command;
+pub mod credential;
+pub mod error;
+pub mod fee;
+pub mod musig;
+pub mod policy;
+pub mod rbf;
+pub mod script;
+pub mod selection;
+pub mod verify;
+mod test;
+
+use bitcoin::{OutPoint, ScriptBuf, Txid};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+pub use error::{Result, SelfCustodyError};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LocalUtxo {
+    pub txid: String,
+    pub vout: u32,
+    pub amount: u64,
+    /// hex encoded script of the output, needed when spending from several custody addresses.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub script_pubkey: Option<String>,
+    /// confirmation height, `None` while unconfirmed. Oldest-first coin selection spends the
+    /// lowest heights first.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+}
+
+impl LocalUtxo {
+    pub fn outpoint(&self) -> Result<OutPoint> {
+        Ok(OutPoint {
+            txid: Txid::from_str(&self.txid)
+                .map_err(|e| SelfCustodyError::InvalidUtxo(e.to_string()))?,
+            vout: self.vout,
+        })
+    }
+
+    pub fn script_pubkey(&self) -> Result<Option<ScriptBuf>> {
+        self.script_pubkey
+            .as_ref()
+            .map(|v| {
+                ScriptBuf::from_hex(v).map_err(|e| SelfCustodyError::InvalidUtxo(e.to_string()))
+            })
+            .transpose()
+    }
+}
@@ -1,90 +1,660 @@
-#![allow(dead_code)]
-
-mod aux;
-mod builder;
-mod command;
-mod script;
-mod test;
-
-use aux::mulsig_address;
 use bitcoin::Network;
-use clap::Parser;
-use command::*;
-use serde::{Deserialize, Serialize};
-
-
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct LocalUtxo {
-    pub txid: String,
-    pub vout: u32,
-    pub amount: u64,
-}
+use clap::{Parser, Subcommand, ValueEnum};
+use selfcustody::builder::{ChainTip, OutputOrdering, SpendType};
+use selfcustody::channel::ChannelParams;
+use selfcustody::command::*;
+use selfcustody::fee::Fee;
+use selfcustody::policy::{CustodyPolicy, NumsKey};
+use selfcustody::selection::{CoinSelection, CoinSelectionAlgorithm};
+use selfcustody::{Result, SelfCustodyError};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
-    #[clap(short, long, value_parser)]
-    secret: String,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print the custody address of the policy
+    Address {
+        #[clap(flatten)]
+        policy: PolicyArgs,
+    },
+    /// Build an unsigned PSBT spending from the custody address
+    Build {
+        #[clap(flatten)]
+        policy: PolicyArgs,
+
+        #[clap(long, value_enum)]
+        path: SpendPath,
+
+        #[clap(flatten)]
+        fee: FeeArgs,
+
+        /// utxos list in json format, `script_pubkey` (hex) is required with `--other-time`, to
+        /// tell which custody address each one comes from.
+        /// example: '[{"txid":"2946d93547be832d3fd63086c3894948a0f13ed29077d00aa5a3c8767ea83497","vout":0,"amount":10000000}]'
+        #[clap(long, value_parser)]
+        utxos: String,
+
+        /// escape times of other custody addresses of the same parties the utxos come from
+        #[clap(long, value_parser, multiple_occurrences = true)]
+        other_time: Vec<u32>,
+
+        /// coin selection when no utxos pay exactly without change, `bnb` fails instead
+        #[clap(long, value_enum, default_value = "largest-first")]
+        coin_selection: Selection,
+
+        /// repeated for several recipients, each with its `--receiver-amount`
+        #[clap(short, long, value_parser, multiple_occurrences = true, required = true)]
+        receiver: Vec<String>,
+
+        /// amounts of the receivers in order, the last receiver has none with `--sweep`
+        #[clap(long, value_parser, multiple_occurrences = true)]
+        receiver_amount: Vec<u64>,
+
+        /// change address, the custody address by default
+        #[clap(long, value_parser, conflicts_with = "sweep")]
+        change: Option<String>,
+
+        /// send everything left after the other receivers and the fee to the last receiver
+        #[clap(long, action)]
+        sweep: bool,
+
+        #[clap(long, value_enum, default_value = "given")]
+        output_order: OutputOrder,
+
+        #[clap(flatten)]
+        tip: TipArgs,
+    },
+    /// Build an unsigned forced withdrawal PSBT moving every custody utxo to the one-way channel
+    Withdraw {
+        #[clap(flatten)]
+        policy: PolicyArgs,
+
+        #[clap(flatten)]
+        fee: FeeArgs,
+
+        /// mapping utxos in json format, same as `build`
+        #[clap(long, value_parser)]
+        utxos: String,
+
+        /// escape times of other custody addresses of the same parties the utxos come from
+        #[clap(long, value_parser, multiple_occurrences = true)]
+        other_time: Vec<u32>,
+
+        /// escape time of the one-way channel of the same committee and validator receiving the
+        /// utxos, after the escape time of the two-way channel
+        #[clap(long, value_parser)]
+        one_way_time: u32,
+
+        #[clap(flatten)]
+        tip: TipArgs,
+    },
+    /// Build an unsigned mapping credential PSBT funding the custody address from wallet utxos
+    Map {
+        #[clap(flatten)]
+        policy: PolicyArgs,
+
+        #[clap(flatten)]
+        fee: FeeArgs,
+
+        /// wallet utxos (p2wpkh or p2tr) in json format, `script_pubkey` (hex) is required.
+        /// example: '[{"txid":"2946d93547be832d3fd63086c3894948a0f13ed29077d00aa5a3c8767ea83497","vout":0,"amount":10000000,"script_pubkey":"0014..."}]'
+        #[clap(long, value_parser)]
+        utxos: String,
 
+        /// amount locked at the custody address
+        #[clap(long, value_parser)]
+        amount: u64,
+
+        /// chain id of the EVM chain receiving the mapping
+        #[clap(long, value_parser)]
+        dst_chain: u32,
+
+        /// 0x prefixed EVM address on the destination chain
+        #[clap(short, long, value_parser)]
+        receiver: String,
+
+        #[clap(long, value_parser)]
+        change: String,
+    },
+    /// Build an unsigned burning credential PSBT co-signed by the mapper and the committee
+    Burn {
+        #[clap(flatten)]
+        policy: PolicyArgs,
+
+        #[clap(flatten)]
+        fee: FeeArgs,
+
+        /// mapping utxos in json format, same as `build`
+        #[clap(long, value_parser)]
+        utxos: String,
+
+        /// escape times of other custody addresses of the same parties the utxos come from
+        #[clap(long, value_parser, multiple_occurrences = true)]
+        other_time: Vec<u32>,
+
+        /// coin selection when no utxos pay exactly without change, `bnb` fails instead
+        #[clap(long, value_enum, default_value = "largest-first")]
+        coin_selection: Selection,
+
+        /// address of the BTC mapper
+        #[clap(long, value_parser)]
+        mapper: String,
+
+        /// amount redeemed to the mapper
+        #[clap(long, value_parser)]
+        amount: u64,
+
+        /// hex unique identifier of the burn
+        #[clap(long, value_parser)]
+        uid: String,
+    },
+    /// Build an unsigned PSBT replacing a stuck custody transaction with a higher fee
+    Bump {
+        #[clap(flatten)]
+        policy: PolicyArgs,
+
+        #[clap(flatten)]
+        fee: FeeArgs,
+
+        /// the signed transaction to replace
+        #[clap(long, value_parser)]
+        tx: String,
+
+        /// outputs spent by the transaction in json format, same as `verify`
+        #[clap(long, value_parser)]
+        prevouts: String,
+
+        /// index of the change output paying the higher fee
+        #[clap(long, value_parser, required_unless_present = "change")]
+        change_output: Option<usize>,
+
+        /// the change output takes everything left and can't be dropped, as in a sweep
+        #[clap(long, action, requires = "change-output")]
+        sweep: bool,
+
+        /// address of a new change output, for an original without one
+        #[clap(long, value_parser, conflicts_with = "change-output")]
+        change: Option<String>,
+
+        /// confirmed utxos added when the change can't pay the higher fee, same as `build`
+        #[clap(long, value_parser, default_value = "[]")]
+        utxos: String,
+
+        /// escape times of other custody addresses of the same parties the inputs come from
+        #[clap(long, value_parser, multiple_occurrences = true)]
+        other_time: Vec<u32>,
+    },
+    /// Add the signatures of a secret key to a PSBT
+    Sign {
+        #[clap(long, value_parser)]
+        psbt: String,
+
+        #[clap(short, long, value_parser)]
+        secret: String,
+    },
+    /// Merge PSBTs signed by different parties
+    Combine {
+        #[clap(long, value_parser, multiple_occurrences = true, required = true)]
+        psbt: Vec<String>,
+    },
+    /// Finalize a fully signed PSBT into a transaction
+    Finalize {
+        #[clap(long, value_parser)]
+        psbt: String,
+    },
+    /// Print the content of a PSBT or a raw transaction
+    Decode {
+        #[clap(value_parser)]
+        data: String,
+    },
+    /// Check the signatures of a PSBT, or run a signed transaction through script verification
+    Verify {
+        #[clap(long, value_parser, required_unless_present = "tx")]
+        psbt: Option<String>,
+
+        #[clap(long, value_parser, conflicts_with = "psbt", requires = "prevouts")]
+        tx: Option<String>,
+
+        /// spent outputs in json format.
+        /// example: '[{"amount":10000000,"script_pubkey":"5120..."}]'
+        #[clap(long, value_parser)]
+        prevouts: Option<String>,
+    },
+}
+
+#[derive(clap::Args, Debug)]
+struct PolicyArgs {
     #[clap(short, long, value_parser)]
     committee: String,
 
-    #[clap(short, long, value_parser)]
+    #[clap(short, long, value_parser, required_unless_present = "one-way")]
     multisign: Option<String>,
 
+    /// escape hatch public key
+    #[clap(long, value_parser)]
+    validator: String,
+
+    /// forced withdrawal unlock time of the committee, the escape time is six months later.
+    /// With `--one-way`, the one of the two-way channels, checked against the one-way escape
+    #[clap(short, long, value_parser, required_unless_present = "one-way")]
+    enforce_time: Option<u32>,
+
+    #[clap(short, long, value_parser)]
+    time: u32,
+
     #[clap(short, long, value_parser)]
-    time: u64,
+    network: u64,
+
+    /// the one-way channel policy: a committee leaf and an escape leaf, the committee key is
+    /// the internal key
+    #[clap(long, action, conflicts_with_all = &["multisign", "nums"])]
+    one_way: bool,
+
+    /// `OP_CSV` enforce and escape leaves, the times count blocks or 512 second intervals
+    /// since the deposit confirms
+    #[clap(long, value_enum)]
+    csv: Option<CsvUnit>,
 
-    /// 0.00001 represents 1 sat/vB
-    #[clap(long, value_parser, default_value = "0.00001")]
-    fee_rate: f64,
+    /// use the unspendable BIP-341 NUMS point as internal key, disabling the key path
+    #[clap(long, action)]
+    nums: bool,
 
-    /// utxos list in json format.
-    /// example: '[{"txid":"2946d93547be832d3fd63086c3894948a0f13ed29077d00aa5a3c8767ea83497","vout":0,"amount":10000000}]'
+    /// hex randomizer r of the NUMS internal key H + rG, keep it to derive the address again
+    #[clap(long, value_parser, requires = "nums")]
+    nums_randomizer: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct FeeArgs {
+    /// sat/vB, up to three decimals
+    #[clap(long, value_parser, default_value = "1")]
+    fee_rate: String,
+
+    /// absolute fee in sat, overrides `--fee-rate`
     #[clap(long, value_parser)]
-    utxos: String,
+    fee: Option<u64>,
 
-    #[clap(short, long, value_parser)]
-    receiver: String,
+    /// abort when the fee is above this amount in sat
+    #[clap(long, value_parser)]
+    max_fee: Option<u64>,
 
+    /// abort when the fee is above this rate in sat/vB
     #[clap(long, value_parser)]
-    receiver_amount: u64,
+    max_fee_rate: Option<String>,
+}
 
-    #[clap(short, long, value_parser)]
-    network: u64,
+#[derive(clap::Args, Debug)]
+struct TipArgs {
+    /// height of the chain tip, checks that the escape or enforce lock is reached
+    #[clap(long, value_parser, requires = "tip-mtp")]
+    tip_height: Option<u32>,
+
+    /// median time past of the chain tip
+    #[clap(long, value_parser, requires = "tip-height")]
+    tip_mtp: Option<u32>,
+}
+
+impl TipArgs {
+    fn tip(&self) -> Option<ChainTip> {
+        self.tip_height.zip(self.tip_mtp).map(|(height, median_time_past)| ChainTip {
+            height,
+            median_time_past,
+        })
+    }
+}
+
+impl FeeArgs {
+    fn fee(&self) -> Result<Fee> {
+        parse_fee(&self.fee_rate, self.fee, self.max_fee, self.max_fee_rate.as_deref())
+    }
+}
+
+#[derive(ValueEnum, Clone, Debug)]
+enum Selection {
+    Bnb,
+    LargestFirst,
+    OldestFirst,
+}
+
+impl Selection {
+    fn algorithm(&self) -> CoinSelectionAlgorithm {
+        match self {
+            Selection::Bnb => CoinSelectionAlgorithm::BranchAndBound,
+            Selection::LargestFirst => CoinSelectionAlgorithm::LargestFirst,
+            Selection::OldestFirst => CoinSelectionAlgorithm::OldestFirst,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Debug)]
+enum OutputOrder {
+    /// the receivers in order, then the change
+    Given,
+    Bip69,
+    Random,
+}
+
+impl OutputOrder {
+    fn ordering(&self) -> OutputOrdering {
+        match self {
+            OutputOrder::Given => OutputOrdering::Given,
+            OutputOrder::Bip69 => OutputOrdering::Bip69,
+            OutputOrder::Random => OutputOrdering::Random,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Debug)]
+enum CsvUnit {
+    Blocks,
+    Intervals,
+}
+
+#[derive(ValueEnum, Clone, Debug)]
+enum SpendPath {
+    Mulsig,
+    Escape,
+    /// the committee leaf of a one-way channel
+    Committee,
+    Key,
+}
+
+impl PolicyArgs {
+    fn network(&self) -> Network {
+        match self.network {
+            0 => Network::Bitcoin,
+            1 => Network::Testnet,
+            2 => Network::Regtest,
+            _ => Network::Testnet,
+        }
+    }
+
+    fn lock(&self) -> LeafLock {
+        match self.csv {
+            None => LeafLock::Absolute,
+            Some(CsvUnit::Blocks) => LeafLock::Blocks,
+            Some(CsvUnit::Intervals) => LeafLock::Intervals,
+        }
+    }
+
+    /// The policy of these arguments followed by the ones with the other escape times.
+    fn policies(&self, other_times: &[u32]) -> Result<Vec<CustodyPolicy>> {
+        let mut policies = vec![self.policy()?];
+        for time in other_times {
+            println!("other unlock time {}", time);
+            policies.push(self.policy_with_time(*time)?);
+        }
+        Ok(policies)
+    }
+
+    fn policy(&self) -> Result<CustodyPolicy> {
+        println!("========= parameters =========");
+        if let Some(multisign) = &self.multisign {
+            println!("multi_signer pk {}", multisign);
+        }
+        println!("commitee pk {}", self.committee);
+        println!("validator pk {}", self.validator);
+        if let Some(enforce_time) = self.enforce_time {
+            println!("enforce time {}", enforce_time);
+        }
+        println!("unlock time {}", self.time);
+        println!("lock {:?}", self.lock());
+        println!("network {}", self.network());
+        if let Some(nums) = self.nums_key()? {
+            println!("nums internal key {}", nums.public_key());
+        }
+        println!("========= ========== =========");
+
+        self.policy_with_time(self.time)
+    }
+
+    /// The forced withdrawal lock of the address escaping at `time`. An absolute escape lock is
+    /// six months after it, so each other escape time of a two-way address has its own.
+    fn enforce_time(&self, time: u32) -> Result<Option<u32>> {
+        if self.one_way || time == self.time || self.lock() != LeafLock::Absolute {
+            return Ok(self.enforce_time);
+        }
+        ChannelParams::default_enforce_time(time).map(Some)
+    }
+
+    /// The lock of a forced withdrawal, reaching the forced withdrawal lock of every address.
+    fn max_enforce_time(&self, other_times: &[u32]) -> Result<u32> {
+        let mut enforce_time = 0;
+        for time in other_times.iter().chain([&self.time]) {
+            enforce_time = enforce_time.max(self.enforce_time(*time)?.unwrap_or(0));
+        }
+        Ok(enforce_time)
+    }
+
+    /// The one-way channel of the same committee and validator escaping at `time`, the
+    /// destination of a forced withdrawal.
+    fn one_way_destination(&self, time: u32) -> Result<CustodyPolicy> {
+        one_way_policy(
+            &self.committee,
+            &self.validator,
+            time,
+            self.enforce_time,
+            self.lock(),
+            self.network(),
+        )
+    }
+
+    fn policy_with_time(&self, time: u32) -> Result<CustodyPolicy> {
+        if self.one_way {
+            return self.one_way_destination(time);
+        }
+        let multisign = self
+            .multisign
+            .as_deref()
+            .expect("clap requires --multisign without --one-way");
+        let enforce_time = self
+            .enforce_time(time)?
+            .expect("clap requires --enforce-time without --one-way");
+        custody_policy(
+            &self.committee,
+            multisign,
+            &self.validator,
+            enforce_time,
+            time,
+            self.lock(),
+            self.nums_key()?,
+            self.network(),
+        )
+    }
+
+    fn nums_key(&self) -> Result<Option<NumsKey>> {
+        if !self.nums {
+            return Ok(None);
+        }
+        parse_nums_key(self.nums_randomizer.as_deref()).map(Some)
+    }
+}
+
+fn print_selection(selection: &CoinSelection) {
+    println!(
+        "{:?} selected {} utxos, fee {} sat",
+        selection.algorithm,
+        selection.selected.len(),
+        selection.fee
+    );
+    for utxo in &selection.unselected {
+        println!("untouched utxo {}:{} {} sat", utxo.txid, utxo.vout, utxo.amount);
+    }
+}
+
+fn run(command: Command) -> Result<String> {
+    match command {
+        Command::Address { policy } => {
+            Ok(format!(">> address: {}", address_command(&policy.policy()?)))
+        }
+        Command::Build {
+            policy,
+            path,
+            fee,
+            utxos,
+            other_time,
+            coin_selection,
+            receiver,
+            mut receiver_amount,
+            change,
+            sweep,
+            output_order,
+            tip,
+        } => {
+            let utxos = parse_utxos(&utxos)?;
+            println!("receive amounts {:?} fee_rate {}", receiver_amount, fee.fee_rate);
+            if sweep {
+                receiver_amount.push(0);
+            }
+            if receiver.len() != receiver_amount.len() {
+                return Err(SelfCustodyError::InvalidAddress(format!(
+                    "{} receivers for {} amounts",
+                    receiver.len(),
+                    receiver_amount.len()
+                )));
+            }
+            let payment = parse_payment(
+                &receiver.into_iter().zip(receiver_amount).collect::<Vec<_>>(),
+                change.as_deref(),
+                sweep,
+                output_order.ordering(),
+                policy.network(),
+            )?;
+            let spent_type = match path {
+                SpendPath::Mulsig => SpendType::MULSIG,
+                // the lock has to reach the escape lock of every address
+                SpendPath::Escape => SpendType::ESCAPE(
+                    other_time.iter().copied().fold(policy.time, u32::max),
+                ),
+                SpendPath::Committee => SpendType::COMMITTEE,
+                SpendPath::Key => SpendType::KEY,
+            };
+            let (psbt, selection) = build_command(
+                &policy.policies(&other_time)?,
+                spent_type,
+                fee.fee()?,
+                &payment,
+                utxos,
+                coin_selection.algorithm(),
+                tip.tip(),
+            )?;
+            if let Some(selection) = selection {
+                print_selection(&selection);
+            }
+            Ok(format!(">> psbt: {}", psbt))
+        }
+        Command::Withdraw {
+            policy,
+            fee,
+            utxos,
+            other_time,
+            one_way_time,
+            tip,
+        } => {
+            let utxos = parse_utxos(&utxos)?;
+            let psbt = withdraw_command(
+                &policy.policies(&other_time)?,
+                policy.max_enforce_time(&other_time)?,
+                fee.fee()?,
+                &policy.one_way_destination(one_way_time)?,
+                utxos,
+                tip.tip(),
+            )?;
+            Ok(format!(">> psbt: {}", psbt))
+        }
+        Command::Map {
+            policy,
+            fee,
+            utxos,
+            amount,
+            dst_chain,
+            receiver,
+            change,
+        } => {
+            let utxos = parse_utxos(&utxos)?;
+            let psbt = map_command(
+                &policy.policy()?,
+                fee.fee()?,
+                amount,
+                dst_chain,
+                &receiver,
+                change,
+                utxos,
+            )?;
+            Ok(format!(">> psbt: {}", psbt))
+        }
+        Command::Burn {
+            policy,
+            fee,
+            utxos,
+            other_time,
+            coin_selection,
+            mapper,
+            amount,
+            uid,
+        } => {
+            let utxos = parse_utxos(&utxos)?;
+            let (psbt, selection) = burn_command(
+                &policy.policies(&other_time)?,
+                fee.fee()?,
+                mapper,
+                amount,
+                &uid,
+                utxos,
+                coin_selection.algorithm(),
+            )?;
+            print_selection(&selection);
+            Ok(format!(">> psbt: {}", psbt))
+        }
+        Command::Bump {
+            policy,
+            fee,
+            tx,
+            prevouts,
+            change_output,
+            sweep,
+            change,
+            utxos,
+            other_time,
+        } => {
+            let utxos = parse_utxos(&utxos)?;
+            let change =
+                parse_bump_change(change_output, sweep, change.as_deref(), policy.network())?;
+            let psbt = bump_command(
+                &policy.policies(&other_time)?,
+                &tx,
+                &prevouts,
+                fee.fee()?,
+                &change,
+                utxos,
+            )?;
+            Ok(format!(">> psbt: {}", psbt))
+        }
+        Command::Sign { psbt, secret } => Ok(format!(">> psbt: {}", sign_command(&psbt, &secret)?)),
+        Command::Combine { psbt } => Ok(format!(">> psbt: {}", combine_command(&psbt)?)),
+        Command::Finalize { psbt } => Ok(format!(">> tx: {}", finalize_command(&psbt)?)),
+        Command::Decode { data } => decode_command(&data),
+        Command::Verify { psbt, tx, prevouts } => match (psbt, tx, prevouts) {
+            (_, Some(tx), Some(prevouts)) => verify_tx_command(&tx, &prevouts),
+            (Some(psbt), _, _) => verify_command(&psbt),
+            _ => unreachable!("clap requires either --psbt or --tx with --prevouts"),
+        },
+    }
 }
 
 fn main() {
     let args = Args::parse();
 
-    println!("========= parameters =========");
-    let private_key_u8 = hex::decode(args.secret).unwrap();
-    let multi_signer = mulsig_address(args.multisign, &private_key_u8);
-    let utxos: Vec<LocalUtxo> = serde_json::from_str(&args.utxos).unwrap();
-    println!("multi_signer pk {}", multi_signer);
-    println!("commitee pk {}", args.committee);
-    println!("unlock time {}", args.time);
-    println!("receive amount {} fee_rate {}", args.receiver_amount, args.fee_rate);
-
-    let network = match args.network {
-        0 => Network::Bitcoin,
-        1 => Network::Testnet,
-        2 => Network::Regtest,
-        _ => Network::Testnet,
-    };
-    println!("network {}", network);
-    println!("========= ========== =========");
-
-    let tx = build_mulsig_escape_command(
-        &private_key_u8,
-        args.time,
-        args.committee,
-        multi_signer,
-        args.fee_rate,
-        args.receiver,
-        args.receiver_amount,
-        network,
-        utxos
-    );
-    println!(">> tx: {}", tx);
-}
\ No newline at end of file
+    match run(args.command) {
+        Ok(output) => println!("{}", output),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(e.exit_code());
+        }
+    }
+}
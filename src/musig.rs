@@ -0,0 +1,462 @@
+//! BIP-327 MuSig2 for the key path of a custody policy. The committee and the mapper aggregate
+//! their keys into the internal key and sign in two rounds, exchanging public nonces and then
+//! partial signatures, so neither party ever learns the secret of the other.
+
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::psbt::Psbt;
+use bitcoin::secp256k1::rand::{thread_rng, RngCore};
+use bitcoin::secp256k1::{schnorr, Parity, PublicKey, Secp256k1, SecretKey, XOnlyPublicKey};
+use bitcoin::sighash::{Prevouts, SighashCache, TapSighashType};
+use bitcoin::taproot::{self, TapNodeHash, TapTweakHash};
+// the libsecp256k1 scalar reduces modulo the curve order, the bitcoin one doesn't
+use secp256k1::curve::Scalar;
+
+use crate::aux::psbt_prevouts;
+use crate::error::{Result, SelfCustodyError};
+
+/// The two key aggregation of the policies: the keys are sorted first so that every party gets
+/// the same internal key whatever the order it was given the keys in.
+pub fn aggregate_keys(keys: &[PublicKey]) -> Result<XOnlyPublicKey> {
+    Ok(KeyAggContext::sorted(keys.to_vec())?.aggregated_key())
+}
+
+/// The aggregated key with the accumulated tweaks, `KeyAggContext` in BIP-327.
+#[derive(Clone, Debug)]
+pub struct KeyAggContext {
+    keys: Vec<PublicKey>,
+    list_hash: [u8; 32],
+    second_key: Option<PublicKey>,
+    q: PublicKey,
+    gacc: Scalar,
+    tacc: Scalar,
+}
+
+impl KeyAggContext {
+    /// Aggregates `keys` in the given order.
+    pub fn new(keys: Vec<PublicKey>) -> Result<Self> {
+        if keys.is_empty() {
+            return Err(musig_error("no key to aggregate"));
+        }
+        let serialized: Vec<[u8; 33]> = keys.iter().map(|v| v.serialize()).collect();
+        let list_hash = tagged_hash("KeyAgg list", &[&serialized.concat()]);
+        let second_key = keys.iter().find(|v| **v != keys[0]).copied();
+
+        let mut ctx = KeyAggContext {
+            keys: vec![],
+            list_hash,
+            second_key,
+            q: keys[0],
+            gacc: Scalar::from_int(1),
+            tacc: Scalar::from_int(0),
+        };
+        let terms = keys
+            .iter()
+            .map(|v| point_mul(v, &ctx.coefficient(v)))
+            .collect::<Result<Vec<_>>>()?;
+        let terms: Vec<&PublicKey> = terms.iter().collect();
+        ctx.q = PublicKey::combine_keys(&terms)
+            .map_err(|_| musig_error("the aggregated key is infinity"))?;
+        ctx.keys = keys;
+        Ok(ctx)
+    }
+
+    /// Aggregates `keys` sorted by their compressed encoding, `KeySort` in BIP-327.
+    pub fn sorted(mut keys: Vec<PublicKey>) -> Result<Self> {
+        keys.sort_by_key(|v| v.serialize());
+        Self::new(keys)
+    }
+
+    /// The x-only aggregated key, tweaks included.
+    pub fn aggregated_key(&self) -> XOnlyPublicKey {
+        self.q.x_only_public_key().0
+    }
+
+    pub fn keys(&self) -> &[PublicKey] {
+        &self.keys
+    }
+
+    /// Applies the BIP-341 tweak of a taproot output whose internal key is the aggregated key, so
+    /// the aggregated signature is valid for the output key.
+    pub fn with_taproot_tweak(self, merkle_root: Option<TapNodeHash>) -> Result<Self> {
+        let tweak = TapTweakHash::from_key_and_tweak(self.aggregated_key(), merkle_root);
+        self.with_tweak(&tweak.to_scalar().to_be_bytes(), true)
+    }
+
+    /// Applies a plain or x-only tweak, `ApplyTweak` in BIP-327.
+    pub fn with_tweak(mut self, tweak: &[u8; 32], is_xonly: bool) -> Result<Self> {
+        let t = scalar_checked(tweak).ok_or(musig_error("the tweak exceeds the curve order"))?;
+        let g = if is_xonly && !has_even_y(&self.q) {
+            -Scalar::from_int(1)
+        } else {
+            Scalar::from_int(1)
+        };
+        let gq = point_mul(&self.q, &g)?;
+        self.q = if t.is_zero() {
+            gq
+        } else {
+            gq.combine(&base_mul(&t)?)
+                .map_err(|_| musig_error("the tweaked key is infinity"))?
+        };
+        self.gacc = g.clone() * self.gacc;
+        self.tacc = t + g * self.tacc;
+        Ok(self)
+    }
+
+    /// `KeyAggCoeff` of `key`, one for the second distinct key.
+    fn coefficient(&self, key: &PublicKey) -> Scalar {
+        if Some(*key) == self.second_key {
+            return Scalar::from_int(1);
+        }
+        scalar(&tagged_hash("KeyAgg coefficient", &[&self.list_hash, &key.serialize()]))
+    }
+
+    /// The factor flipping the aggregated key to an even y, `g` in BIP-327.
+    fn parity_factor(&self) -> Scalar {
+        if has_even_y(&self.q) {
+            Scalar::from_int(1)
+        } else {
+            -Scalar::from_int(1)
+        }
+    }
+}
+
+/// The secret nonce of a signer. It isn't `Clone` and signing consumes it, reusing a nonce for
+/// two messages leaks the secret key.
+pub struct SecNonce {
+    k1: Scalar,
+    k2: Scalar,
+    public_key: PublicKey,
+}
+
+// the serialization of the test vectors, a secret nonce never leaves the signer otherwise
+#[cfg(test)]
+impl SecNonce {
+    pub(crate) fn serialize(&self) -> [u8; 97] {
+        let mut bytes = [0; 97];
+        bytes[..32].copy_from_slice(&self.k1.b32());
+        bytes[32..64].copy_from_slice(&self.k2.b32());
+        bytes[64..].copy_from_slice(&self.public_key.serialize());
+        bytes
+    }
+
+    pub(crate) fn from_slice(bytes: &[u8; 97]) -> Result<Self> {
+        let k = |v: &[u8]| {
+            scalar_checked(v.try_into().expect("32 bytes"))
+                .filter(|k| !k.is_zero())
+                .ok_or(musig_error("the secret nonce is zero or exceeds the curve order"))
+        };
+        Ok(SecNonce {
+            k1: k(&bytes[..32])?,
+            k2: k(&bytes[32..64])?,
+            public_key: PublicKey::from_slice(&bytes[64..])
+                .map_err(|e| musig_error(&format!("secret nonce: {}", e)))?,
+        })
+    }
+}
+
+/// The public nonce sent to the other signers in the first round.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PubNonce {
+    r1: PublicKey,
+    r2: PublicKey,
+}
+
+impl PubNonce {
+    pub fn serialize(&self) -> [u8; 66] {
+        let mut bytes = [0; 66];
+        bytes[..33].copy_from_slice(&self.r1.serialize());
+        bytes[33..].copy_from_slice(&self.r2.serialize());
+        bytes
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != 66 {
+            return Err(musig_error("a public nonce is 66 bytes"));
+        }
+        let point = |v: &[u8]| {
+            PublicKey::from_slice(v).map_err(|e| musig_error(&format!("public nonce: {}", e)))
+        };
+        Ok(PubNonce {
+            r1: point(&bytes[..33])?,
+            r2: point(&bytes[33..])?,
+        })
+    }
+}
+
+/// The sum of the public nonces, either point may be infinity.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AggNonce {
+    r1: Option<PublicKey>,
+    r2: Option<PublicKey>,
+}
+
+impl AggNonce {
+    /// `NonceAgg` in BIP-327.
+    pub fn new(nonces: &[PubNonce]) -> Result<Self> {
+        if nonces.is_empty() {
+            return Err(musig_error("no public nonce to aggregate"));
+        }
+        let sum = |points: Vec<&PublicKey>| PublicKey::combine_keys(&points).ok();
+        Ok(AggNonce {
+            r1: sum(nonces.iter().map(|v| &v.r1).collect()),
+            r2: sum(nonces.iter().map(|v| &v.r2).collect()),
+        })
+    }
+
+    /// Infinity is encoded as 33 zero bytes.
+    pub fn serialize(&self) -> [u8; 66] {
+        let mut bytes = [0; 66];
+        if let Some(r1) = self.r1 {
+            bytes[..33].copy_from_slice(&r1.serialize());
+        }
+        if let Some(r2) = self.r2 {
+            bytes[33..].copy_from_slice(&r2.serialize());
+        }
+        bytes
+    }
+}
+
+/// A partial signature of the second round.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PartialSignature(Scalar);
+
+impl PartialSignature {
+    pub fn serialize(&self) -> [u8; 32] {
+        self.0.b32()
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Result<Self> {
+        let bytes: [u8; 32] = bytes
+            .try_into()
+            .map_err(|_| musig_error("a partial signature is 32 bytes"))?;
+        scalar_checked(&bytes)
+            .map(PartialSignature)
+            .ok_or(musig_error("the partial signature exceeds the curve order"))
+    }
+}
+
+/// Generates the nonce of the signer of `secret` for signing `msg` with the key of `ctx`,
+/// `NonceGen` in BIP-327 with fresh randomness and every optional input given.
+pub fn generate_nonce(
+    ctx: &KeyAggContext,
+    secret: &SecretKey,
+    msg: &[u8; 32],
+) -> Result<(SecNonce, PubNonce)> {
+    let mut rand = [0; 32];
+    thread_rng().fill_bytes(&mut rand);
+    let public_key = secret.public_key(&Secp256k1::new());
+    let aggregated_key = ctx.aggregated_key().serialize();
+    nonce_gen(rand, Some(secret), public_key, Some(&aggregated_key), Some(msg), &[])
+}
+
+/// `NonceGen` in BIP-327 with the randomness `rand`, the other inputs are optional.
+pub(crate) fn nonce_gen(
+    mut rand: [u8; 32],
+    secret: Option<&SecretKey>,
+    public_key: PublicKey,
+    aggregated_key: Option<&[u8; 32]>,
+    msg: Option<&[u8]>,
+    extra_in: &[u8],
+) -> Result<(SecNonce, PubNonce)> {
+    if let Some(secret) = secret {
+        let aux = tagged_hash("MuSig/aux", &[&rand]);
+        for (r, (s, a)) in rand.iter_mut().zip(secret.secret_bytes().iter().zip(aux)) {
+            *r = s ^ a;
+        }
+    }
+    let aggregated_key: &[u8] = aggregated_key.map_or(&[], |v| v);
+    let msg_prefixed = match msg {
+        Some(msg) => [&[1], &(msg.len() as u64).to_be_bytes()[..], msg].concat(),
+        None => vec![0],
+    };
+
+    let k = |i: u8| {
+        scalar(&tagged_hash(
+            "MuSig/nonce",
+            &[
+                &rand,
+                &[33],
+                &public_key.serialize(),
+                &[aggregated_key.len() as u8],
+                aggregated_key,
+                &msg_prefixed,
+                &(extra_in.len() as u32).to_be_bytes(),
+                extra_in,
+                &[i],
+            ],
+        ))
+    };
+    let (k1, k2) = (k(0), k(1));
+    let pub_nonce = PubNonce {
+        r1: base_mul(&k1)?,
+        r2: base_mul(&k2)?,
+    };
+    Ok((SecNonce { k1, k2, public_key }, pub_nonce))
+}
+
+/// The signing session of one message once every public nonce is known.
+pub struct Session<'a> {
+    ctx: &'a KeyAggContext,
+    b: Scalar,
+    r: PublicKey,
+    e: Scalar,
+}
+
+impl<'a> Session<'a> {
+    /// `GetSessionValues` in BIP-327.
+    pub fn new(ctx: &'a KeyAggContext, agg_nonce: &AggNonce, msg: &[u8; 32]) -> Result<Self> {
+        let q = ctx.aggregated_key().serialize();
+        let b = scalar(&tagged_hash("MuSig/noncecoef", &[&agg_nonce.serialize(), &q, msg]));
+        let br2 = match agg_nonce.r2 {
+            Some(r2) if !b.is_zero() => Some(point_mul(&r2, &b)?),
+            _ => None,
+        };
+        let r = match (agg_nonce.r1, br2) {
+            (Some(r1), Some(br2)) => r1.combine(&br2).ok(),
+            (r1, br2) => r1.or(br2),
+        }
+        .unwrap_or(base_mul(&Scalar::from_int(1))?);
+        let e = scalar(&tagged_hash(
+            "BIP0340/challenge",
+            &[&r.x_only_public_key().0.serialize(), &q, msg],
+        ));
+        Ok(Session { ctx, b, r, e })
+    }
+
+    /// The partial signature of the signer of `secret`, `Sign` in BIP-327.
+    pub fn partial_sign(
+        &self,
+        sec_nonce: SecNonce,
+        secret: &SecretKey,
+    ) -> Result<PartialSignature> {
+        let public_key = secret.public_key(&Secp256k1::new());
+        if public_key != sec_nonce.public_key {
+            return Err(musig_error("the secret nonce belongs to another key"));
+        }
+        if !self.ctx.keys.contains(&public_key) {
+            return Err(musig_error("the key is not one of the aggregated keys"));
+        }
+        let (mut k1, mut k2) = (sec_nonce.k1.clone(), sec_nonce.k2.clone());
+        if !has_even_y(&self.r) {
+            k1 = -k1;
+            k2 = -k2;
+        }
+        let d = scalar(&secret.secret_bytes());
+        let d = self.ctx.parity_factor() * self.ctx.gacc.clone() * d;
+        let a = self.ctx.coefficient(&public_key);
+        Ok(PartialSignature(k1 + self.b.clone() * k2 + self.e.clone() * a * d))
+    }
+
+    /// Checks the partial signature of the signer of `public_key`, `PartialSigVerify` in BIP-327.
+    pub fn verify_partial(
+        &self,
+        psig: &PartialSignature,
+        pub_nonce: &PubNonce,
+        public_key: &PublicKey,
+    ) -> Result<()> {
+        if !self.ctx.keys.contains(public_key) {
+            return Err(musig_error("the key is not one of the aggregated keys"));
+        }
+        let invalid = || musig_error(&format!("invalid partial signature of {}", public_key));
+        let mut re = pub_nonce.r1;
+        if !self.b.is_zero() {
+            re = re.combine(&point_mul(&pub_nonce.r2, &self.b)?).map_err(|_| invalid())?;
+        }
+        if !has_even_y(&self.r) {
+            re = re.negate(&Secp256k1::new());
+        }
+        let factor = self.e.clone()
+            * self.ctx.coefficient(public_key)
+            * self.ctx.parity_factor()
+            * self.ctx.gacc.clone();
+        let expected = re.combine(&point_mul(public_key, &factor)?).map_err(|_| invalid())?;
+        if psig.0.is_zero() || base_mul(&psig.0)? != expected {
+            return Err(invalid());
+        }
+        Ok(())
+    }
+
+    /// The BIP-340 signature of the aggregated key, `PartialSigAgg` in BIP-327.
+    pub fn aggregate(&self, psigs: &[PartialSignature]) -> Result<schnorr::Signature> {
+        let mut s = self.e.clone() * self.ctx.parity_factor() * self.ctx.tacc.clone();
+        for psig in psigs {
+            s += &psig.0;
+        }
+        let mut sig = [0; 64];
+        sig[..32].copy_from_slice(&self.r.x_only_public_key().0.serialize());
+        sig[32..].copy_from_slice(&s.b32());
+        schnorr::Signature::from_slice(&sig).map_err(|e| musig_error(&e.to_string()))
+    }
+}
+
+/// The BIP-341 key path sighash of `input`, the message of the signing session.
+pub fn key_spend_message(psbt: &Psbt, input: usize) -> Result<[u8; 32]> {
+    let prevouts = psbt_prevouts(psbt)?;
+    SighashCache::new(&psbt.unsigned_tx)
+        .taproot_key_spend_signature_hash(input, &Prevouts::All(&prevouts), TapSighashType::Default)
+        .map(|v| v.to_byte_array())
+        .map_err(|e| SelfCustodyError::Sighash(e.to_string()))
+}
+
+/// Stores the aggregated signature of `input` as its key path signature.
+pub fn add_key_spend_signature(
+    psbt: &mut Psbt,
+    input: usize,
+    sig: schnorr::Signature,
+) -> Result<()> {
+    let input = psbt
+        .inputs
+        .get_mut(input)
+        .ok_or(SelfCustodyError::InvalidPsbt(format!("no input {}", input)))?;
+    input.tap_key_sig = Some(taproot::Signature {
+        sig,
+        hash_ty: TapSighashType::Default,
+    });
+    Ok(())
+}
+
+fn musig_error(reason: &str) -> SelfCustodyError {
+    SelfCustodyError::MuSig(reason.to_string())
+}
+
+fn tagged_hash(tag: &str, data: &[&[u8]]) -> [u8; 32] {
+    let tag = sha256::Hash::hash(tag.as_bytes());
+    let mut engine = sha256::Hash::engine();
+    engine.input(tag.as_ref());
+    engine.input(tag.as_ref());
+    for v in data {
+        engine.input(v);
+    }
+    sha256::Hash::from_engine(engine).to_byte_array()
+}
+
+/// `bytes` as an integer modulo the curve order.
+fn scalar(bytes: &[u8; 32]) -> Scalar {
+    let mut s = Scalar::default();
+    let _ = s.set_b32(bytes);
+    s
+}
+
+fn scalar_checked(bytes: &[u8; 32]) -> Option<Scalar> {
+    let mut s = Scalar::default();
+    let overflow: bool = s.set_b32(bytes).into();
+    (!overflow).then_some(s)
+}
+
+fn point_mul(point: &PublicKey, s: &Scalar) -> Result<PublicKey> {
+    let tweak = bitcoin::secp256k1::Scalar::from_be_bytes(s.b32())
+        .map_err(|_| musig_error("the scalar exceeds the curve order"))?;
+    point
+        .mul_tweak(&Secp256k1::new(), &tweak)
+        .map_err(|_| musig_error("point multiplication by zero"))
+}
+
+fn base_mul(s: &Scalar) -> Result<PublicKey> {
+    let secret =
+        SecretKey::from_slice(&s.b32()).map_err(|_| musig_error("the nonce is zero"))?;
+    Ok(secret.public_key(&Secp256k1::new()))
+}
+
+fn has_even_y(point: &PublicKey) -> bool {
+    point.x_only_public_key().1 == Parity::Even
+}
@@ -0,0 +1,244 @@
+use std::str::FromStr;
+
+use bitcoin::secp256k1::rand::thread_rng;
+use bitcoin::secp256k1::{Parity, Scalar, Secp256k1, SecretKey, XOnlyPublicKey};
+use bitcoin::taproot::{ControlBlock, LeafVersion, TaprootBuilder, TaprootSpendInfo};
+use bitcoin::{Address, Network, ScriptBuf, TapLeafHash, TapNodeHash};
+
+use crate::error::{Result, SelfCustodyError};
+
+/// The x coordinate of the BIP-341 NUMS point `H`, the hash of the generator lifted to the curve.
+/// Nobody knows its discrete logarithm.
+pub const NUMS_POINT: &str = "50929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac0";
+
+/// The script leaves of a custody output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Leaf {
+    Mulsig,
+    Enforce,
+    Escape,
+    /// The committee single-sig leaf of a one-way channel.
+    Committee,
+}
+
+/// A provably unspendable internal key, `H + rG` for a randomizer `r` or `H` itself. The random
+/// `r` keeps the outputs from being recognized as script-only, revealing it proves the key path
+/// can't be used.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NumsKey {
+    randomizer: Option<[u8; 32]>,
+    key: XOnlyPublicKey,
+}
+
+impl NumsKey {
+    /// `H + rG`, or `H` without `randomizer`. `r` must be below the curve order.
+    pub fn new(randomizer: Option<[u8; 32]>) -> Result<Self> {
+        let h = XOnlyPublicKey::from_str(NUMS_POINT).expect("H is on the curve");
+        let key = match randomizer {
+            Some(r) => {
+                let r = Scalar::from_be_bytes(r).map_err(|e| {
+                    SelfCustodyError::InvalidKey(format!("nums randomizer: {}", e))
+                })?;
+                h.public_key(Parity::Even)
+                    .add_exp_tweak(&Secp256k1::verification_only(), &r)
+                    .map_err(|e| SelfCustodyError::InvalidKey(e.to_string()))?
+                    .x_only_public_key()
+                    .0
+            }
+            None => h,
+        };
+        Ok(NumsKey { randomizer, key })
+    }
+
+    /// `H + rG` with a fresh `r`, which must be kept to derive the policy again.
+    pub fn random() -> Self {
+        let r = SecretKey::new(&mut thread_rng()).secret_bytes();
+        Self::new(Some(r)).expect("a secret key is below the curve order")
+    }
+
+    pub fn randomizer(&self) -> Option<[u8; 32]> {
+        self.randomizer
+    }
+
+    pub fn public_key(&self) -> XOnlyPublicKey {
+        self.key
+    }
+
+    /// Checks the proof that `key` has no known secret: it is `H + rG` for the given `r`.
+    pub fn verify(key: XOnlyPublicKey, randomizer: Option<[u8; 32]>) -> bool {
+        Self::new(randomizer).is_ok_and(|v| v.key == key)
+    }
+}
+
+/// The internal key of a custody output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InternalKey {
+    /// A key its owners can spend through the key path with.
+    Key(XOnlyPublicKey),
+    /// An unspendable key, the output can only be spent through its leaves.
+    Nums(NumsKey),
+}
+
+impl InternalKey {
+    pub fn public_key(&self) -> XOnlyPublicKey {
+        match self {
+            InternalKey::Key(key) => *key,
+            InternalKey::Nums(nums) => nums.public_key(),
+        }
+    }
+}
+
+impl From<XOnlyPublicKey> for InternalKey {
+    fn from(key: XOnlyPublicKey) -> Self {
+        InternalKey::Key(key)
+    }
+}
+
+impl From<NumsKey> for InternalKey {
+    fn from(nums: NumsKey) -> Self {
+        InternalKey::Nums(nums)
+    }
+}
+
+/// The taproot tree of a custody output. Address derivation and spending both go through this
+/// type so the leaf order and depths can't drift apart.
+#[derive(Clone, Debug)]
+pub struct CustodyPolicy {
+    internal_key: InternalKey,
+    network: Network,
+    leaves: Vec<(Leaf, ScriptBuf)>,
+    spend_info: TaprootSpendInfo,
+}
+
+impl CustodyPolicy {
+    /// The mapping output tree. The multi-sign leaf is the cooperative path and sits at depth 1,
+    /// the enforcing and escaping leaves share the other branch.
+    pub fn new(
+        internal_key: impl Into<InternalKey>,
+        mulsig_script: ScriptBuf,
+        enforce_script: ScriptBuf,
+        escape_script: ScriptBuf,
+        network: Network,
+    ) -> Self {
+        Self::from_leaves(
+            internal_key.into(),
+            vec![
+                (1, Leaf::Mulsig, mulsig_script),
+                (2, Leaf::Enforce, enforce_script),
+                (2, Leaf::Escape, escape_script),
+            ],
+            network,
+        )
+    }
+
+    /// The one-way channel tree: the committee leaf and the escape leaf, whose lock is longer
+    /// than the one of the two-way channels, both at depth 1.
+    pub fn one_way(
+        internal_key: impl Into<InternalKey>,
+        committee_script: ScriptBuf,
+        escape_script: ScriptBuf,
+        network: Network,
+    ) -> Self {
+        Self::from_leaves(
+            internal_key.into(),
+            vec![
+                (1, Leaf::Committee, committee_script),
+                (1, Leaf::Escape, escape_script),
+            ],
+            network,
+        )
+    }
+
+    /// `leaves` are `(depth, leaf, script)` in DFS order.
+    fn from_leaves(
+        internal_key: InternalKey,
+        leaves: Vec<(u8, Leaf, ScriptBuf)>,
+        network: Network,
+    ) -> Self {
+        let secp = Secp256k1::verification_only();
+        let mut builder = TaprootBuilder::new();
+        for (depth, _, script) in leaves.iter() {
+            builder = builder
+                .add_leaf(*depth, script.clone())
+                .expect("adding leaf should work");
+        }
+        let spend_info = builder
+            .finalize(&secp, internal_key.public_key())
+            .expect("finalizing taproot builder should work");
+
+        CustodyPolicy {
+            internal_key,
+            network,
+            leaves: leaves
+                .into_iter()
+                .map(|(_, leaf, script)| (leaf, script))
+                .collect(),
+            spend_info,
+        }
+    }
+
+    pub fn internal_key(&self) -> XOnlyPublicKey {
+        self.internal_key.public_key()
+    }
+
+    /// The unspendable internal key, `None` when the output can be spent through the key path.
+    pub fn nums_key(&self) -> Option<NumsKey> {
+        match self.internal_key {
+            InternalKey::Key(_) => None,
+            InternalKey::Nums(nums) => Some(nums),
+        }
+    }
+
+    pub fn has_key_path(&self) -> bool {
+        self.nums_key().is_none()
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    pub fn leaves(&self) -> &[(Leaf, ScriptBuf)] {
+        &self.leaves
+    }
+
+    pub fn has_leaf(&self, leaf: Leaf) -> bool {
+        self.leaves.iter().any(|(l, _)| *l == leaf)
+    }
+
+    /// Fails when `leaf` is not part of the tree, see `has_leaf`.
+    pub fn script(&self, leaf: Leaf) -> Result<&ScriptBuf> {
+        self.leaves
+            .iter()
+            .find(|(l, _)| *l == leaf)
+            .map(|(_, script)| script)
+            .ok_or(SelfCustodyError::MissingLeaf(leaf))
+    }
+
+    pub fn spend_info(&self) -> &TaprootSpendInfo {
+        &self.spend_info
+    }
+
+    pub fn merkle_root(&self) -> Option<TapNodeHash> {
+        self.spend_info.merkle_root()
+    }
+
+    pub fn address(&self) -> Address {
+        Address::p2tr_tweaked(self.spend_info.output_key(), self.network)
+    }
+
+    pub fn script_pubkey(&self) -> ScriptBuf {
+        self.address().script_pubkey()
+    }
+
+    pub fn control_block(&self, leaf: Leaf) -> Result<ControlBlock> {
+        let script = self.script(leaf)?.clone();
+        Ok(self
+            .spend_info
+            .control_block(&(script, LeafVersion::TapScript))
+            .expect("the leaf is part of the tree"))
+    }
+
+    pub fn leaf_hash(&self, leaf: Leaf) -> Result<TapLeafHash> {
+        Ok(TapLeafHash::from_script(self.script(leaf)?, LeafVersion::TapScript))
+    }
+}
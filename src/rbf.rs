@@ -0,0 +1,149 @@
+//! Fee bumping: a BIP-125 replacement of a custody transaction stuck with too low a fee.
+
+use std::cmp::Reverse;
+
+use bitcoin::psbt::Psbt;
+use bitcoin::{Amount, ScriptBuf, Transaction, TxOut};
+
+use crate::builder::{build_custody_tx, SpendType};
+use crate::classify::spent_leaf;
+use crate::error::{Result, SelfCustodyError};
+use crate::fee::Fee;
+use crate::policy::{CustodyPolicy, Leaf};
+use crate::LocalUtxo;
+
+/// The output of the replaced transaction paying the higher fee, the other outputs are kept.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BumpChange {
+    /// The change output at this index, dropped when it falls below dust.
+    Output(usize),
+    /// The output at this index takes everything left, as in a sweep or a forced withdrawal.
+    Sweep(usize),
+    /// The original has no change output, one paying to this script is added when above dust.
+    New(ScriptBuf),
+}
+
+/// Rebuilds the signed `original`, spending `prevouts`, into an unsigned replacement paying
+/// `fee`, and at least the fee of the original plus its own relay at a higher rate. The spend
+/// path and lock time are kept. `change` pays the difference; confirmed `utxos` are added,
+/// largest first, when it can't.
+pub fn bump_fee(
+    original: &Transaction,
+    prevouts: &[TxOut],
+    fee: Fee,
+    policies: &[CustodyPolicy],
+    change: &BumpChange,
+    utxos: Vec<LocalUtxo>,
+) -> Result<Psbt> {
+    if prevouts.len() != original.input.len() {
+        return Err(SelfCustodyError::InvalidTransaction(format!(
+            "{} prevouts for {} inputs",
+            prevouts.len(),
+            original.input.len()
+        )));
+    }
+    if !original.is_explicitly_rbf() {
+        return Err(SelfCustodyError::InvalidTransaction(format!(
+            "{} does not signal BIP-125 replaceability",
+            original.txid()
+        )));
+    }
+    let spent: u64 = prevouts.iter().map(|v| v.value.to_sat()).sum();
+    let paid: u64 = original.output.iter().map(|v| v.value.to_sat()).sum();
+    let original_fee = spent.checked_sub(paid).ok_or(SelfCustodyError::InvalidTransaction(
+        format!("outputs of {} sat spend {} sat", paid, spent),
+    ))?;
+    let fee = fee.replacing(Amount::from_sat(original_fee), original.weight());
+    let spent_type = replaced_spend_type(original, prevouts, policies)?;
+
+    let inputs: Vec<LocalUtxo> = original
+        .input
+        .iter()
+        .zip(prevouts)
+        .map(|(input, prevout)| LocalUtxo {
+            txid: input.previous_output.txid.to_string(),
+            vout: input.previous_output.vout,
+            amount: prevout.value.to_sat(),
+            script_pubkey: Some(prevout.script_pubkey.to_hex_string()),
+            height: None,
+        })
+        .collect();
+    let mut outputs = original.output.clone();
+    let (change, sweep) = match change {
+        BumpChange::Output(i) | BumpChange::Sweep(i) if *i >= outputs.len() => {
+            return Err(SelfCustodyError::InvalidTransaction(format!(
+                "no output {} in {} outputs",
+                i,
+                outputs.len()
+            )))
+        }
+        BumpChange::Output(i) => (outputs.remove(*i).script_pubkey, false),
+        BumpChange::Sweep(i) => (outputs.remove(*i).script_pubkey, true),
+        BumpChange::New(script_pubkey) => (script_pubkey.clone(), false),
+    };
+    // BIP-125 rule 2: no unconfirmed inputs the original doesn't spend
+    if let Some(utxo) = utxos.iter().find(|v| v.height.is_none()) {
+        return Err(SelfCustodyError::InvalidUtxo(format!(
+            "{}:{} is unconfirmed, a replacement can't add it",
+            utxo.txid, utxo.vout
+        )));
+    }
+
+    let mut utxos = utxos;
+    utxos.sort_by_key(|v| Reverse(v.amount));
+    for added in 0..=utxos.len() {
+        let spent = inputs.iter().chain(&utxos[..added]).cloned().collect();
+        match build_custody_tx(
+            spent_type,
+            policies,
+            spent,
+            fee,
+            outputs.clone(),
+            change.clone(),
+            sweep,
+        ) {
+            Err(SelfCustodyError::InsufficientFunds { .. }) if added < utxos.len() => {}
+            result => return result,
+        }
+    }
+    unreachable!("the last attempt spends every utxo")
+}
+
+/// The single path the inputs of `original` spend, read from their witnesses.
+fn replaced_spend_type(
+    original: &Transaction,
+    prevouts: &[TxOut],
+    policies: &[CustodyPolicy],
+) -> Result<SpendType> {
+    let lock_time = original.lock_time.to_consensus_u32();
+    let mut spent_type = None;
+    for (i, (input, prevout)) in original.input.iter().zip(prevouts).enumerate() {
+        let policy = policies
+            .iter()
+            .find(|policy| policy.script_pubkey() == prevout.script_pubkey)
+            .ok_or(SelfCustodyError::InvalidUtxo(format!(
+                "input {} does not spend from any of the custody policies",
+                i
+            )))?;
+        let input_type = match spent_leaf(input, policy) {
+            Some(Leaf::Mulsig) => SpendType::MULSIG,
+            Some(Leaf::Escape) => SpendType::ESCAPE(lock_time),
+            Some(Leaf::Enforce) => SpendType::ENFORCE(lock_time),
+            Some(Leaf::Committee) => SpendType::COMMITTEE,
+            None if input.witness.len() == 1 => SpendType::KEY,
+            None => {
+                return Err(SelfCustodyError::InvalidTransaction(format!(
+                    "input {} is not a signed spend of its custody policy",
+                    i
+                )))
+            }
+        };
+        if spent_type.is_some_and(|v| v != input_type) {
+            return Err(SelfCustodyError::InvalidTransaction(
+                "the inputs spend different paths".to_string(),
+            ));
+        }
+        spent_type = Some(input_type);
+    }
+    spent_type.ok_or(SelfCustodyError::InvalidTransaction("no inputs".to_string()))
+}
@@ -1,6 +1,25 @@
-use bitcoin::opcodes::all::{OP_CHECKSIG, OP_CHECKSIGVERIFY, OP_CLTV, OP_DROP};
-use bitcoin::script::Builder;
+use bitcoin::absolute;
+use bitcoin::locktime::relative;
+use bitcoin::opcodes::all::{
+    OP_CHECKSIG, OP_CHECKSIGADD, OP_CHECKSIGVERIFY, OP_CLTV, OP_CSV, OP_DROP, OP_NUMEQUAL,
+};
+use bitcoin::script::{Builder, Instruction, Script};
 use bitcoin::secp256k1::XOnlyPublicKey;
+use bitcoin::Sequence;
+
+use crate::error::{Result, SelfCustodyError};
+
+/// Most keys a `build_multi_a` leaf can hold, the BIP-342 stack limit allows 999.
+pub const MAX_MULTI_A_KEYS: usize = 999;
+
+/// The lock a leaf script waits for before its key can spend.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LeafTimelock {
+    /// `OP_CLTV`, satisfied by the transaction `nLockTime`.
+    Absolute(absolute::LockTime),
+    /// `OP_CSV`, satisfied by the input `nSequence` of a version 2 transaction.
+    Relative(relative::LockTime),
+}
 
 pub fn build_mulsig2(committee_pk: XOnlyPublicKey, project_party_pk: XOnlyPublicKey) -> Builder {
     Builder::new()
@@ -10,7 +29,7 @@ pub fn build_mulsig2(committee_pk: XOnlyPublicKey, project_party_pk: XOnlyPublic
         .push_opcode(OP_CHECKSIG)
 }
 
-pub fn build_escape(release_time: u64, project_party_pk: XOnlyPublicKey) -> Builder {
+pub fn build_escape(release_time: u32, project_party_pk: XOnlyPublicKey) -> Builder {
     Builder::new()
         .push_int(release_time as i64)
         .push_opcode(OP_CLTV)
@@ -18,3 +37,128 @@ pub fn build_escape(release_time: u64, project_party_pk: XOnlyPublicKey) -> Buil
         .push_x_only_key(&project_party_pk)
         .push_opcode(OP_CHECKSIG)
 }
+
+/// Forced withdrawal leaf: the committee alone can move the funds once `release_time` passed.
+/// The enforcing lock is expected to expire well before the escaping one.
+pub fn build_enforce(release_time: u32, committee_pk: XOnlyPublicKey) -> Builder {
+    Builder::new()
+        .push_int(release_time as i64)
+        .push_opcode(OP_CLTV)
+        .push_opcode(OP_DROP)
+        .push_x_only_key(&committee_pk)
+        .push_opcode(OP_CHECKSIG)
+}
+
+/// `threshold` of `keys` leaf, `<k1> OP_CHECKSIG <k2> OP_CHECKSIGADD ... <t> OP_NUMEQUAL`. The
+/// witness holds one item per key in reverse order, empty for the keys not signing.
+pub fn build_multi_a(threshold: usize, keys: &[XOnlyPublicKey]) -> Result<Builder> {
+    if threshold == 0 || threshold > keys.len() || keys.len() > MAX_MULTI_A_KEYS {
+        return Err(SelfCustodyError::InvalidKey(format!(
+            "threshold {} of {} keys",
+            threshold,
+            keys.len()
+        )));
+    }
+    let mut builder = Builder::new();
+    for (i, key) in keys.iter().enumerate() {
+        builder = builder
+            .push_x_only_key(key)
+            .push_opcode(if i == 0 { OP_CHECKSIG } else { OP_CHECKSIGADD });
+    }
+    Ok(builder.push_int(threshold as i64).push_opcode(OP_NUMEQUAL))
+}
+
+/// The signatures a leaf needs: the threshold of a `build_multi_a` leaf, one per key otherwise.
+pub fn required_signatures(script: &Script) -> usize {
+    multi_a_threshold(script).unwrap_or_else(|| script_keys(script).len())
+}
+
+fn multi_a_threshold(script: &Script) -> Option<usize> {
+    let instructions = script
+        .instructions()
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .ok()?;
+    let (numequal, rest) = instructions.split_last()?;
+    let (threshold, keys) = rest.split_last()?;
+    if !matches!(numequal, Instruction::Op(OP_NUMEQUAL)) || keys.is_empty() || keys.len() % 2 != 0
+    {
+        return None;
+    }
+    for (i, pair) in keys.chunks(2).enumerate() {
+        let checksig = if i == 0 { OP_CHECKSIG } else { OP_CHECKSIGADD };
+        match pair {
+            [Instruction::PushBytes(key), Instruction::Op(op)]
+                if key.len() == 32 && *op == checksig => {}
+            _ => return None,
+        }
+    }
+    usize::try_from(threshold.script_num()?).ok()
+}
+
+/// Escaping leaf with a BIP-68 relative lock, counted from the confirmation of the deposit.
+pub fn build_escape_csv(lock: relative::LockTime, project_party_pk: XOnlyPublicKey) -> Builder {
+    Builder::new()
+        .push_sequence(relative_sequence(lock))
+        .push_opcode(OP_CSV)
+        .push_opcode(OP_DROP)
+        .push_x_only_key(&project_party_pk)
+        .push_opcode(OP_CHECKSIG)
+}
+
+/// Forced withdrawal leaf with a BIP-68 relative lock, see `build_escape_csv`.
+pub fn build_enforce_csv(lock: relative::LockTime, committee_pk: XOnlyPublicKey) -> Builder {
+    Builder::new()
+        .push_sequence(relative_sequence(lock))
+        .push_opcode(OP_CSV)
+        .push_opcode(OP_DROP)
+        .push_x_only_key(&committee_pk)
+        .push_opcode(OP_CHECKSIG)
+}
+
+/// The `nSequence` encoding of `lock`, in blocks or 512 second units.
+pub fn relative_sequence(lock: relative::LockTime) -> Sequence {
+    match lock {
+        relative::LockTime::Blocks(height) => Sequence::from_height(height.value()),
+        relative::LockTime::Time(time) => Sequence::from_512_second_intervals(time.value()),
+    }
+}
+
+/// The `<n> OP_CLTV` or `<n> OP_CSV` lock of a leaf script, if any.
+pub fn script_timelock(script: &Script) -> Option<LeafTimelock> {
+    let instructions = script
+        .instructions()
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .ok()?;
+    instructions.windows(2).find_map(|pair| {
+        let n = u32::try_from(pair[0].script_num()?).ok()?;
+        match pair[1] {
+            Instruction::Op(OP_CLTV) => Some(LeafTimelock::Absolute(
+                absolute::LockTime::from_consensus(n),
+            )),
+            Instruction::Op(OP_CSV) => Sequence::from_consensus(n)
+                .to_relative_lock_time()
+                .map(LeafTimelock::Relative),
+            _ => None,
+        }
+    })
+}
+
+/// One-way channel leaf: the committee alone can move the funds at any time.
+pub fn build_committee(committee_pk: XOnlyPublicKey) -> Builder {
+    Builder::new()
+        .push_x_only_key(&committee_pk)
+        .push_opcode(OP_CHECKSIG)
+}
+
+/// The x-only keys pushed by a leaf script, in script order.
+pub fn script_keys(script: &Script) -> Vec<XOnlyPublicKey> {
+    script
+        .instructions()
+        .filter_map(|ins| match ins {
+            Ok(Instruction::PushBytes(bytes)) if bytes.len() == 32 => {
+                XOnlyPublicKey::from_slice(bytes.as_bytes()).ok()
+            }
+            _ => None,
+        })
+        .collect()
+}
@@ -0,0 +1,200 @@
+//! Coin selection: picks the utxos a transaction spends out of a wallet's list instead of
+//! spending all of them.
+
+use bitcoin::Weight;
+
+use crate::error::{Result, SelfCustodyError};
+use crate::fee::Fee;
+use crate::LocalUtxo;
+
+/// Nodes the branch and bound search visits before falling back.
+const BNB_MAX_TRIES: usize = 100_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CoinSelectionAlgorithm {
+    /// Inputs covering the outputs and the fee without a change output above dust.
+    BranchAndBound,
+    /// The largest utxos first, for the fewest inputs.
+    LargestFirst,
+    /// The utxos confirmed the earliest first, unconfirmed ones last.
+    OldestFirst,
+}
+
+/// The transaction the inputs are selected for.
+#[derive(Clone, Copy, Debug)]
+pub struct SelectionTarget {
+    /// Sum of the outputs other than the change.
+    pub amount: u64,
+    /// Weight of the transaction without inputs, change output included.
+    pub base_weight: u64,
+    /// The change is dropped, left to the fee, below this value.
+    pub change_dust: u64,
+    pub fee: Fee,
+}
+
+#[derive(Clone, Debug)]
+pub struct CoinSelection {
+    pub algorithm: CoinSelectionAlgorithm,
+    pub selected: Vec<LocalUtxo>,
+    /// The utxos left untouched, in their original order.
+    pub unselected: Vec<LocalUtxo>,
+    /// Fee of the transaction with its change output.
+    pub fee: u64,
+}
+
+/// Selects from `candidates`, utxos with the weight of the input spending them, witness
+/// included. A changeless branch and bound solution is searched first, `fallback` is used when
+/// there is none, and `BranchAndBound` as fallback fails instead.
+pub fn select_coins(
+    candidates: Vec<(LocalUtxo, u64)>,
+    target: &SelectionTarget,
+    fallback: CoinSelectionAlgorithm,
+) -> Result<CoinSelection> {
+    let mut algorithm = CoinSelectionAlgorithm::BranchAndBound;
+    let mut selected = branch_and_bound(&candidates, target)?;
+    if selected.is_none() {
+        algorithm = fallback;
+        let mut order: Vec<usize> = (0..candidates.len()).collect();
+        match fallback {
+            CoinSelectionAlgorithm::BranchAndBound => {}
+            CoinSelectionAlgorithm::LargestFirst => {
+                order.sort_by_key(|i| std::cmp::Reverse(candidates[*i].0.amount));
+                selected = accumulate(&candidates, &order, target)?;
+            }
+            CoinSelectionAlgorithm::OldestFirst => {
+                order.sort_by_key(|i| candidates[*i].0.height.unwrap_or(u32::MAX));
+                selected = accumulate(&candidates, &order, target)?;
+            }
+        }
+    }
+
+    let Some(mut selected) = selected else {
+        let weight = candidates.iter().map(|(_, w)| w).sum();
+        let available = candidates.iter().map(|(v, _)| v.amount).sum();
+        let needed = target.amount + fee(target, weight)?;
+        if available >= needed {
+            return Err(SelfCustodyError::InvalidUtxo(format!(
+                "no utxos pay {} sat without change",
+                target.amount
+            )));
+        }
+        return Err(SelfCustodyError::InsufficientFunds { available, needed });
+    };
+    selected.sort();
+    let weight = selected.iter().map(|i| candidates[*i].1).sum();
+    let mut selection = CoinSelection {
+        algorithm,
+        selected: vec![],
+        unselected: vec![],
+        fee: fee(target, weight)?,
+    };
+    for (i, (utxo, _)) in candidates.into_iter().enumerate() {
+        if selected.binary_search(&i).is_ok() {
+            selection.selected.push(utxo);
+        } else {
+            selection.unselected.push(utxo);
+        }
+    }
+    Ok(selection)
+}
+
+/// Fee of the transaction with inputs of `input_weight` in total.
+fn fee(target: &SelectionTarget, input_weight: u64) -> Result<u64> {
+    let weight = Weight::from_wu(target.base_weight + input_weight);
+    Ok(target.fee.amount(weight)?.to_sat())
+}
+
+/// Depth first search over the utxos by decreasing effective value, the amount less the fee of
+/// its input, for the selection wasting the least above the target.
+fn branch_and_bound(
+    candidates: &[(LocalUtxo, u64)],
+    target: &SelectionTarget,
+) -> Result<Option<Vec<usize>>> {
+    let mut pool = vec![];
+    for (i, (utxo, weight)) in candidates.iter().enumerate() {
+        let input_fee = fee(target, *weight)? - fee(target, 0)?;
+        // a utxo not paying for its own input is never worth spending here
+        if utxo.amount > input_fee {
+            pool.push((i, utxo.amount - input_fee));
+        }
+    }
+    pool.sort_by_key(|(_, value)| std::cmp::Reverse(*value));
+
+    let low = target.amount + fee(target, 0)?;
+    let mut search = BnbSearch {
+        pool: &pool,
+        low,
+        high: low + target.change_dust,
+        tries: BNB_MAX_TRIES,
+        selected: vec![],
+        best: None,
+    };
+    let remaining = pool.iter().map(|(_, v)| v).sum();
+    search.run(0, 0, remaining);
+    let Some((_, selected)) = search.best else {
+        return Ok(None);
+    };
+
+    // the fee of the whole transaction may round apart from the sum of the input fees
+    let total: u64 = selected.iter().map(|i| candidates[*i].0.amount).sum();
+    let weight = selected.iter().map(|i| candidates[*i].1).sum();
+    let needed = target.amount + fee(target, weight)?;
+    if total < needed || total - needed >= target.change_dust {
+        return Ok(None);
+    }
+    Ok(Some(selected))
+}
+
+struct BnbSearch<'a> {
+    pool: &'a [(usize, u64)],
+    low: u64,
+    high: u64,
+    tries: usize,
+    selected: Vec<usize>,
+    /// The lowest excess over `low` found and its selection.
+    best: Option<(u64, Vec<usize>)>,
+}
+
+impl BnbSearch<'_> {
+    fn run(&mut self, index: usize, value: u64, remaining: u64) {
+        if self.tries == 0 || value > self.high {
+            return;
+        }
+        self.tries -= 1;
+        if value >= self.low {
+            let excess = value - self.low;
+            if self.best.as_ref().is_none_or(|(best, _)| excess < *best) {
+                self.best = Some((excess, self.selected.clone()));
+            }
+            return;
+        }
+        if index == self.pool.len() || value + remaining < self.low {
+            return;
+        }
+
+        let (i, effective) = self.pool[index];
+        self.selected.push(i);
+        self.run(index + 1, value + effective, remaining - effective);
+        self.selected.pop();
+        self.run(index + 1, value, remaining - effective);
+    }
+}
+
+/// Adds the utxos in `order` until they pay for the outputs and the fee.
+fn accumulate(
+    candidates: &[(LocalUtxo, u64)],
+    order: &[usize],
+    target: &SelectionTarget,
+) -> Result<Option<Vec<usize>>> {
+    let mut selected = vec![];
+    let (mut total, mut weight) = (0, 0);
+    for i in order {
+        selected.push(*i);
+        total += candidates[*i].0.amount;
+        weight += candidates[*i].1;
+        if total >= target.amount + fee(target, weight)? {
+            return Ok(Some(selected));
+        }
+    }
+    Ok(None)
+}
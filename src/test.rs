@@ -1,21 +1,36 @@
 #[cfg(test)]
 mod tests {
-    use bitcoin::key::{Keypair, TapTweak};
+    use bitcoin::consensus::encode;
+    use bitcoin::key::Keypair;
+    use bitcoin::psbt::Psbt;
+    use bitcoin::sighash::TapSighashType;
     use bitcoin::secp256k1::{rand, Secp256k1};
-    use bitcoin::secp256k1::{PublicKey, XOnlyPublicKey};
-    use bitcoin::{Address, Network};
+    use bitcoin::secp256k1::{PublicKey, SecretKey, XOnlyPublicKey};
+    use bitcoin::locktime::relative;
+    use bitcoin::{Address, Amount, Network, Sequence, Transaction, TxOut, Weight, Witness};
     use std::str::FromStr;
 
-    use secp256k1::curve::Scalar;
-
     use crate::{aux::*, LocalUtxo};
     use crate::builder::*;
+    use crate::channel::*;
+    use crate::classify::*;
+    use crate::command::{
+        custody_policy, one_way_policy, parse_fee, parse_fee_rate, withdraw_command, LeafLock,
+    };
+    use crate::credential::*;
+    use crate::error::SelfCustodyError;
+    use crate::fee::Fee;
+    use crate::musig::*;
+    use crate::policy::*;
+    use crate::rbf::{bump_fee, BumpChange};
     use crate::script::*;
+    use crate::selection::*;
+    use crate::verify::verify_transaction;
 
     pub fn create_account(
         committee_secret: Vec<u8>,
         project_party_secret: Vec<u8>,
-    ) -> (XOnlyPublicKey, XOnlyPublicKey, XOnlyPublicKey, Keypair) {
+    ) -> (XOnlyPublicKey, XOnlyPublicKey, XOnlyPublicKey, KeyAggContext) {
         let secp = Secp256k1::new();
         let committee_keypair = Keypair::from_seckey_slice(&secp, &committee_secret).unwrap();
         let committee_xonly = XOnlyPublicKey::from_keypair(&committee_keypair);
@@ -24,36 +39,37 @@ mod tests {
             Keypair::from_seckey_slice(&secp, &project_party_secret).unwrap();
         let project_party_xonly = XOnlyPublicKey::from_keypair(&project_party_keypair);
 
-        // add secrets
-        let array_bytes: Result<[u8; 32], _> = committee_secret.as_slice().try_into();
-        let mut scalar1 = Scalar::default();
-        let _ = scalar1.set_b32(&array_bytes.unwrap());
-        let array_bytes: Result<[u8; 32], _> = project_party_secret.as_slice().try_into();
-        let mut scalar2 = Scalar::default();
-        let _ = scalar2.set_b32(&array_bytes.unwrap());
-        let scalar3 = scalar1 + scalar2;
-
-        let sum_keypair = Keypair::from_seckey_slice(&secp, &scalar3.b32()).unwrap();
-        let sum_xonly = XOnlyPublicKey::from_keypair(&sum_keypair);
-
-        // add publics
-        let combined_pk = committee_keypair
-            .public_key()
-            .combine(&project_party_keypair.public_key())
-            .unwrap();
-        let combined_xonly = combined_pk.x_only_public_key();
-        assert_eq!(combined_xonly.0, sum_xonly.0);
-        assert_eq!(combined_xonly.1, sum_xonly.1);
+        // aggregate publics, no party knows the secret of the internal key
+        let ctx = KeyAggContext::sorted(vec![
+            committee_keypair.public_key(),
+            project_party_keypair.public_key(),
+        ])
+        .unwrap();
+        assert_eq!(
+            ctx.aggregated_key(),
+            aggregate_keys(&[project_party_keypair.public_key(), committee_keypair.public_key()])
+                .unwrap()
+        );
 
-        // assert
         (
             committee_xonly.0,
             project_party_xonly.0,
-            sum_xonly.0,
-            sum_keypair,
+            ctx.aggregated_key(),
+            ctx,
         )
     }
 
+    fn sat_vb(rate: u64) -> Fee {
+        Fee::from_sat_per_vb(rate).unwrap()
+    }
+
+    fn psbt_prevouts(psbt: &Psbt) -> Vec<TxOut> {
+        psbt.inputs
+            .iter()
+            .map(|input| input.witness_utxo.clone().unwrap())
+            .collect()
+    }
+
     #[test]
     fn test_generate_key() {
         let secp = Secp256k1::new();
@@ -63,6 +79,35 @@ mod tests {
         println!("key pair pk only: {:?}", hex::encode(pk.serialize()));
     }
 
+    #[test]
+    fn test_policy_control_blocks() {
+        let secp = Secp256k1::new();
+        let keypair1 = Keypair::new(&secp, &mut rand::thread_rng());
+        let keypair2 = Keypair::new(&secp, &mut rand::thread_rng());
+        let s1 = keypair1.secret_bytes().to_vec();
+        let s2 = keypair2.secret_bytes().to_vec();
+        let (a1, a2, sum, _) = create_account(s1, s2);
+
+        let policy = CustodyPolicy::new(
+            sum,
+            build_mulsig2(a1, a2).into_script(),
+            build_enforce(100, a1).into_script(),
+            build_escape(110, a2).into_script(),
+            Network::Regtest,
+        );
+        let output_key = policy.spend_info().output_key().to_inner();
+        assert_eq!(
+            policy.address().script_pubkey(),
+            Address::p2tr(&secp, sum, policy.merkle_root(), Network::Regtest).script_pubkey()
+        );
+        for (leaf, script) in policy.leaves() {
+            assert!(policy
+                .control_block(*leaf)
+                .unwrap()
+                .verify_taproot_commitment(&secp, output_key, script));
+        }
+    }
+
     #[test]
     fn test_spent_with_mulsig_script() {
         let secp = Secp256k1::new();
@@ -84,46 +129,53 @@ mod tests {
         let (a1, a2, sum, _) = create_account(s1.clone(), s2.clone());
 
         let mulsig2_script_builder = build_mulsig2(a1, a2);
+        let enforce_script_builder = build_enforce(100, a1);
         let escape_script_builder = build_escape(110, a2);
 
-        let mulsig2_addr = build_p2tr_tweaked(
+        let policy = CustodyPolicy::new(
             sum,
-            mulsig2_script_builder.clone().into_script(),
-            escape_script_builder.clone().into_script(),
+            mulsig2_script_builder.into_script(),
+            enforce_script_builder.into_script(),
+            escape_script_builder.into_script(),
             Network::Regtest,
         );
-        println!("mulsig2 addr: {}", mulsig2_addr);
+        println!("mulsig2 addr: {}", policy.address());
 
         let out_points: Vec<LocalUtxo> = vec![LocalUtxo {
             txid: "0b78bd9e57b99e83bb1b5f1a1c1ecd8ae801fcb62f41cb62f986b38090354b65".to_string(),
             vout: 1,
             amount: 100000000,
+            script_pubkey: None,
+            height: None,
         }];
         let receiver =
             Address::from_str("bcrt1pz7y5ps533cnjg8vhgjct6zt4zta8pc9tym6j39v52c37rj8tce7qzrzxj5")
                 .unwrap()
                 .assume_checked();
         let receiver_amount = 10000000;
-        let fee_rate = 0.00001;
+        let fee = sat_vb(1);
 
-        let (tx, sighashs, _) = build_tx(
+        let mut psbt = build_tx(
             SpendType::MULSIG,
-            sum,
-            mulsig2_script_builder.into_script(),
-            escape_script_builder.into_script(),
-            Network::Regtest,
+            &[policy],
             out_points,
-            fee_rate,
-            receiver,
-            receiver_amount,
-        );
+            fee,
+            &Payment::to(receiver, Amount::from_sat(receiver_amount)),
+        )
+        .unwrap();
 
-        // sign by two users
-        let sig1 = sign_transaction(sighashs.clone(), &s1);
-        let sig2 = sign_transaction(sighashs, &s2);
+        // sign by two users, each on their own copy of the psbt
+        let mut psbt2 = Psbt::deserialize(&psbt.serialize()).unwrap();
+        sign_psbt(&mut psbt, &s1).unwrap();
+        sign_psbt(&mut psbt2, &s2).unwrap();
+        psbt.combine(psbt2).unwrap();
+        assert_eq!(psbt.inputs[0].tap_script_sigs.len(), 2);
 
-        // combine the transaction.
-        let tx_hex = combine_multi_sign_transaction(tx, sig1, sig2);
+        // finalize the transaction.
+        let prevouts = psbt_prevouts(&psbt);
+        let tx = finalize_psbt(psbt).unwrap();
+        verify_transaction(&tx, &prevouts).unwrap();
+        let tx_hex = encode::serialize_hex(&tx);
 
         // broadcast to bitcoin
         println!("{}", tx_hex);
@@ -140,50 +192,283 @@ mod tests {
 
         let lock_block = 110;
         let mulsig2_script_builder = build_mulsig2(a1, a2);
+        let enforce_script_builder = build_enforce(100, a1);
         let escape_script_builder = build_escape(lock_block, a2);
 
-        let mulsig2_addr = build_p2tr_tweaked(
+        let policy = CustodyPolicy::new(
             sum,
-            mulsig2_script_builder.clone().into_script(),
-            escape_script_builder.clone().into_script(),
+            mulsig2_script_builder.into_script(),
+            enforce_script_builder.into_script(),
+            escape_script_builder.into_script(),
             Network::Regtest,
         );
-        println!("mulsig2 addr: {}", mulsig2_addr);
+        println!("mulsig2 addr: {}", policy.address());
 
         let out_points: Vec<LocalUtxo> = vec![LocalUtxo {
             txid: "b88cd14973cab9cd59d7e0e4f9fb36425ec671583fefce8c8d1341a848589ebe".to_string(),
             vout: 1,
             amount: 89999538,
+            script_pubkey: None,
+            height: None,
         }];
         let receiver =
             Address::from_str("bcrt1pz7y5ps533cnjg8vhgjct6zt4zta8pc9tym6j39v52c37rj8tce7qzrzxj5")
                 .unwrap()
                 .assume_checked();
         let receiver_amount = 10000000;
-        let fee_rate = 0.00005;
+        let fee = sat_vb(5);
+
+        let mut psbt = build_tx(
+            SpendType::ESCAPE(lock_block),
+            std::slice::from_ref(&policy),
+            out_points,
+            fee,
+            &Payment::to(receiver, Amount::from_sat(receiver_amount)),
+        )
+        .unwrap();
+
+        // sign by project party
+        sign_psbt(&mut psbt, &s2).unwrap();
+
+        // finalize the transaction.
+        let prevouts = psbt_prevouts(&psbt);
+        let tx = finalize_psbt(psbt).unwrap();
+        verify_transaction(&tx, &prevouts).unwrap();
+        let classification = classify_transaction(&tx, &policy);
+        assert_eq!(classification.tx_type, TxType::EscapeHatch);
+        assert!(classification.violations.is_empty());
+
+        // the signature commits to the spent amount and the locktime to the script
+        let mut wrong_prevouts = prevouts.clone();
+        wrong_prevouts[0].value = Amount::from_sat(1);
+        assert!(verify_transaction(&tx, &wrong_prevouts).is_err());
+        let mut early_tx = tx.clone();
+        early_tx.lock_time = bitcoin::absolute::LockTime::from_consensus(109);
+        assert!(matches!(
+            verify_transaction(&early_tx, &prevouts),
+            Err(SelfCustodyError::ScriptVerification { input: 0, .. })
+        ));
+        let tx_hex = encode::serialize_hex(&tx);
+
+        // broadcast to bitcoin
+        println!("{}", tx_hex);
+    }
+
+    #[test]
+    fn test_spent_with_enforce_script() {
+        let secp = Secp256k1::new();
+        let keypair1 = Keypair::new(&secp, &mut rand::thread_rng());
+        let keypair2 = Keypair::new(&secp, &mut rand::thread_rng());
+        let s1 = keypair1.secret_bytes().to_vec();
+        let s2 = keypair2.secret_bytes().to_vec();
+        let (a1, a2, sum, _) = create_account(s1.clone(), s2.clone());
 
-        let (tx, sighashs, _) = build_tx(
-            SpendType::ESCAPE(lock_block as u32),
+        let lock_block = 100;
+        let mulsig2_script_builder = build_mulsig2(a1, a2);
+        let enforce_script_builder = build_enforce(lock_block, a1);
+        let escape_script_builder = build_escape(110, a2);
+
+        let policy = CustodyPolicy::new(
             sum,
             mulsig2_script_builder.into_script(),
+            enforce_script_builder.into_script(),
             escape_script_builder.into_script(),
             Network::Regtest,
-            out_points,
-            fee_rate,
-            receiver,
-            receiver_amount,
         );
+        println!("mulsig2 addr: {}", policy.address());
 
-        // sign by project party
-        let sig1 = sign_transaction(sighashs.clone(), &s2);
+        let out_points: Vec<LocalUtxo> = vec![LocalUtxo {
+            txid: "b88cd14973cab9cd59d7e0e4f9fb36425ec671583fefce8c8d1341a848589ebe".to_string(),
+            vout: 1,
+            amount: 89999538,
+            script_pubkey: None,
+            height: None,
+        }];
+        let receiver =
+            Address::from_str("bcrt1pz7y5ps533cnjg8vhgjct6zt4zta8pc9tym6j39v52c37rj8tce7qzrzxj5")
+                .unwrap()
+                .assume_checked();
+        let receiver_amount = 10000000;
+        let fee = sat_vb(5);
+
+        let mut psbt = build_tx(
+            SpendType::ENFORCE(lock_block),
+            std::slice::from_ref(&policy),
+            out_points,
+            fee,
+            &Payment::to(receiver, Amount::from_sat(receiver_amount)),
+        )
+        .unwrap();
+        assert_eq!(psbt.unsigned_tx.lock_time.to_consensus_u32(), lock_block);
+
+        // sign by committee
+        sign_psbt(&mut psbt, &s1).unwrap();
 
-        // combine the transaction.
-        let tx_hex = combine_escape_transaction(tx, sig1);
+        // finalize the transaction.
+        let prevouts = psbt_prevouts(&psbt);
+        let tx = finalize_psbt(psbt).unwrap();
+        verify_transaction(&tx, &prevouts).unwrap();
+
+        // the change output breaks the single output rule of a forced withdrawal
+        let classification = classify_transaction(&tx, &policy);
+        assert_eq!(classification.tx_type, TxType::ForcedWithdrawal);
+        assert_eq!(classification.inputs, vec![Some(Leaf::Enforce)]);
+        assert_eq!(classification.violations.len(), 1);
+        let tx_hex = encode::serialize_hex(&tx);
 
         // broadcast to bitcoin
         println!("{}", tx_hex);
     }
 
+    #[test]
+    fn test_spent_from_two_custody_addresses() {
+        let secp = Secp256k1::new();
+        let keypair1 = Keypair::new(&secp, &mut rand::thread_rng());
+        let keypair2 = Keypair::new(&secp, &mut rand::thread_rng());
+        let s1 = keypair1.secret_bytes().to_vec();
+        let s2 = keypair2.secret_bytes().to_vec();
+        let (a1, a2, sum, _) = create_account(s1, s2.clone());
+
+        // same parties, two deposits with different escape times
+        let policies: Vec<CustodyPolicy> = [110, 120]
+            .iter()
+            .map(|time| {
+                CustodyPolicy::new(
+                    sum,
+                    build_mulsig2(a1, a2).into_script(),
+                    build_enforce(100, a1).into_script(),
+                    build_escape(*time, a2).into_script(),
+                    Network::Regtest,
+                )
+            })
+            .collect();
+        let out_points: Vec<LocalUtxo> = vec![
+            LocalUtxo {
+                txid: "b88cd14973cab9cd59d7e0e4f9fb36425ec671583fefce8c8d1341a848589ebe"
+                    .to_string(),
+                vout: 1,
+                amount: 50000000,
+                script_pubkey: Some(policies[0].script_pubkey().to_hex_string()),
+                height: None,
+            },
+            LocalUtxo {
+                txid: "0b78bd9e57b99e83bb1b5f1a1c1ecd8ae801fcb62f41cb62f986b38090354b65"
+                    .to_string(),
+                vout: 0,
+                amount: 50000000,
+                script_pubkey: Some(policies[1].script_pubkey().to_hex_string()),
+                height: None,
+            },
+        ];
+        let receiver =
+            Address::from_str("bcrt1pz7y5ps533cnjg8vhgjct6zt4zta8pc9tym6j39v52c37rj8tce7qzrzxj5")
+                .unwrap()
+                .assume_checked();
+
+        let mut psbt = build_tx(
+            SpendType::ESCAPE(120),
+            &policies,
+            out_points.clone(),
+            sat_vb(1),
+            &Payment::to(receiver.clone(), Amount::from_sat(10000000)),
+        )
+        .unwrap();
+        assert_ne!(
+            psbt.inputs[0].witness_utxo.as_ref().unwrap().script_pubkey,
+            psbt.inputs[1].witness_utxo.as_ref().unwrap().script_pubkey
+        );
+        sign_psbt(&mut psbt, &s2).unwrap();
+        let prevouts = psbt_prevouts(&psbt);
+        let tx = finalize_psbt(psbt).unwrap();
+        verify_transaction(&tx, &prevouts).unwrap();
+
+        // a utxo from an address no policy describes is rejected
+        let payment = Payment::to(receiver, Amount::from_sat(10000000));
+        let err = build_tx(
+            SpendType::ESCAPE(120),
+            &policies[..1],
+            out_points.clone(),
+            sat_vb(1),
+            &payment,
+        )
+        .unwrap_err();
+        assert!(matches!(err, SelfCustodyError::InvalidUtxo(_)));
+
+        // without its script a utxo only goes to the policy when there is a single one
+        let mut unknown = out_points[1].clone();
+        unknown.script_pubkey = None;
+        assert!(input_policy(&unknown, &policies[1..]).is_ok());
+        assert!(matches!(input_policy(&unknown, &policies), Err(SelfCustodyError::InvalidUtxo(_))));
+        let err = build_tx(SpendType::ESCAPE(120), &policies, vec![unknown], sat_vb(1), &payment)
+            .unwrap_err();
+        assert!(matches!(err, SelfCustodyError::InvalidUtxo(_)));
+    }
+
+    #[test]
+    fn test_build_and_finalize_errors() {
+        let secp = Secp256k1::new();
+        let keypair1 = Keypair::new(&secp, &mut rand::thread_rng());
+        let keypair2 = Keypair::new(&secp, &mut rand::thread_rng());
+        let s1 = keypair1.secret_bytes().to_vec();
+        let s2 = keypair2.secret_bytes().to_vec();
+        let (a1, a2, sum, _) = create_account(s1.clone(), s2);
+
+        let policy = CustodyPolicy::new(
+            sum,
+            build_mulsig2(a1, a2).into_script(),
+            build_enforce(100, a1).into_script(),
+            build_escape(110, a2).into_script(),
+            Network::Regtest,
+        );
+        let out_points: Vec<LocalUtxo> = vec![LocalUtxo {
+            txid: "0b78bd9e57b99e83bb1b5f1a1c1ecd8ae801fcb62f41cb62f986b38090354b65".to_string(),
+            vout: 1,
+            amount: 100000,
+            script_pubkey: None,
+            height: None,
+        }];
+        let receiver =
+            Address::from_str("bcrt1pz7y5ps533cnjg8vhgjct6zt4zta8pc9tym6j39v52c37rj8tce7qzrzxj5")
+                .unwrap()
+                .assume_checked();
+
+        let err = build_tx(
+            SpendType::MULSIG,
+            std::slice::from_ref(&policy),
+            out_points.clone(),
+            sat_vb(1),
+            &Payment::to(receiver.clone(), Amount::from_sat(100000)),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            SelfCustodyError::InsufficientFunds { available: 100000, .. }
+        ));
+
+        // only the committee signed the multi-sign leaf
+        let payment = Payment::to(receiver, Amount::from_sat(10000));
+        let mut psbt =
+            build_tx(SpendType::MULSIG, &[policy], out_points, sat_vb(1), &payment).unwrap();
+        sign_psbt(&mut psbt, &s1).unwrap();
+        assert_eq!(
+            finalize_psbt(psbt).unwrap_err(),
+            SelfCustodyError::SignatureCountMismatch {
+                input: 0,
+                expected: 2,
+                found: 1
+            }
+        );
+        assert_eq!(
+            parse_address(
+                "bcrt1pz7y5ps533cnjg8vhgjct6zt4zta8pc9tym6j39v52c37rj8tce7qzrzxj5",
+                Network::Bitcoin
+            )
+            .unwrap_err()
+            .exit_code(),
+            3
+        );
+    }
+
     #[test]
     fn test_spent_with_key() {
         let secp = Secp256k1::new();
@@ -191,51 +476,1863 @@ mod tests {
         let keypair2 = Keypair::new(&secp, &mut rand::thread_rng());
         let s1 = keypair1.secret_bytes().to_vec();
         let s2 = keypair2.secret_bytes().to_vec();
-        let (a1, a2, sum, sum_pair) = create_account(s1.clone(), s2.clone());
+        let (a1, a2, sum, ctx) = create_account(s1.clone(), s2.clone());
 
         let mulsig2_script_builder = build_mulsig2(a1, a2);
+        let enforce_script_builder = build_enforce(100, a1);
         let escape_script_builder = build_escape(110, a2);
 
-        let mulsig2_addr = build_p2tr_tweaked(
+        let policy = CustodyPolicy::new(
             sum,
-            mulsig2_script_builder.clone().into_script(),
-            escape_script_builder.clone().into_script(),
+            mulsig2_script_builder.into_script(),
+            enforce_script_builder.into_script(),
+            escape_script_builder.into_script(),
             Network::Regtest,
         );
-        println!("mulsig2 addr: {}", mulsig2_addr);
+        println!("mulsig2 addr: {}", policy.address());
 
         let out_points: Vec<LocalUtxo> = vec![LocalUtxo {
             txid: "0b78bd9e57b99e83bb1b5f1a1c1ecd8ae801fcb62f41cb62f986b38090354b65".to_string(),
             vout: 1,
             amount: 100000000,
+            script_pubkey: None,
+            height: None,
         }];
         let receiver =
             Address::from_str("bcrt1pz7y5ps533cnjg8vhgjct6zt4zta8pc9tym6j39v52c37rj8tce7qzrzxj5")
                 .unwrap()
                 .assume_checked();
         let receiver_amount = 10000000u64;
-        let fee_rate = 0.00003;
+        let fee = sat_vb(3);
 
-        let (tx, sighashs, tweaked_hash) = build_tx(
+        let mut psbt = build_tx(
             SpendType::KEY,
-            sum,
-            mulsig2_script_builder.into_script(),
-            escape_script_builder.into_script(),
-            Network::Regtest,
+            std::slice::from_ref(&policy),
             out_points,
-            fee_rate,
-            receiver,
-            receiver_amount,
-        );
+            fee,
+            &Payment::to(receiver, Amount::from_sat(receiver_amount)),
+        )
+        .unwrap();
+
+        // neither party can sign alone
+        sign_psbt(&mut psbt, &s1).unwrap();
+        sign_psbt(&mut psbt, &s2).unwrap();
+        assert!(psbt.inputs[0].tap_key_sig.is_none());
 
-        // sign by tweaked key
-        let tweaked_keypair: Keypair = sum_pair.tap_tweak(&secp, tweaked_hash).into();
-        let sig1 = sign_transaction(sighashs.clone(), &tweaked_keypair.secret_bytes());
+        // MuSig2 with the taproot tweak of the output key
+        let ctx = ctx.with_taproot_tweak(policy.merkle_root()).unwrap();
+        assert_eq!(ctx.aggregated_key(), policy.spend_info().output_key().to_inner());
+        let msg = key_spend_message(&psbt, 0).unwrap();
 
-        // combine the transaction.
-        let tx_hex = combine_key_transaction(tx, sig1);
+        // first round, the parties exchange their public nonces
+        let secret1 = keypair1.secret_key();
+        let secret2 = keypair2.secret_key();
+        let (sec_nonce1, pub_nonce1) = generate_nonce(&ctx, &secret1, &msg).unwrap();
+        let (sec_nonce2, pub_nonce2) = generate_nonce(&ctx, &secret2, &msg).unwrap();
+        let pub_nonce2 = PubNonce::from_slice(&pub_nonce2.serialize()).unwrap();
+        let agg_nonce = AggNonce::new(&[pub_nonce1, pub_nonce2]).unwrap();
+
+        // second round, the parties exchange their partial signatures
+        let session = Session::new(&ctx, &agg_nonce, &msg).unwrap();
+        let psig1 = session.partial_sign(sec_nonce1, &secret1).unwrap();
+        let psig2 = session.partial_sign(sec_nonce2, &secret2).unwrap();
+        let psig2 = PartialSignature::from_slice(&psig2.serialize()).unwrap();
+        session
+            .verify_partial(&psig1, &pub_nonce1, &keypair1.public_key())
+            .unwrap();
+        session
+            .verify_partial(&psig2, &pub_nonce2, &keypair2.public_key())
+            .unwrap();
+        assert!(session
+            .verify_partial(&psig1, &pub_nonce2, &keypair2.public_key())
+            .is_err());
+
+        let sig = session.aggregate(&[psig1, psig2]).unwrap();
+        add_key_spend_signature(&mut psbt, 0, sig).unwrap();
+
+        // finalize the transaction.
+        let prevouts = psbt_prevouts(&psbt);
+        let tx = finalize_psbt(psbt).unwrap();
+        verify_transaction(&tx, &prevouts).unwrap();
+        let tx_hex = encode::serialize_hex(&tx);
 
         // broadcast to bitcoin
         println!("{}", tx_hex);
     }
+
+    #[test]
+    fn test_musig_key_aggregation() {
+        // BIP-327 key_agg_vectors.json
+        let keys: Vec<PublicKey> = [
+            "02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9",
+            "03DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+            "023590A94E768F8E1815C2F24B4D80A8E3149316C3518CE7B7AD338368D038CA66",
+        ]
+        .iter()
+        .map(|v| PublicKey::from_str(v).unwrap())
+        .collect();
+        let cases: [(&[usize], &str); 4] = [
+            (&[0, 1, 2], "90539EEDE565F5D054F32CC0C220126889ED1E5D193BAF15AEF344FE59D4610C"),
+            (&[2, 1, 0], "6204DE8B083426DC6EAF9502D27024D53FC826BF7D2012148A0575435DF54B2B"),
+            (&[0, 0, 0], "B436E3BAD62B8CD409969A224731C193D051162D8C5AE8B109306127DA3AA935"),
+            (&[0, 0, 1, 1], "69BC22BFA5D106306E48A20679DE1D7389386124D07571D0D872686028C26A3E"),
+        ];
+        for (indices, expected) in cases {
+            let ctx = KeyAggContext::new(indices.iter().map(|i| keys[*i]).collect()).unwrap();
+            assert_eq!(ctx.aggregated_key(), XOnlyPublicKey::from_str(expected).unwrap());
+        }
+
+        // the sorted aggregation doesn't depend on the order of the keys
+        assert_eq!(
+            aggregate_keys(&[keys[0], keys[1], keys[2]]).unwrap(),
+            aggregate_keys(&[keys[2], keys[0], keys[1]]).unwrap()
+        );
+        assert!(matches!(KeyAggContext::new(vec![]), Err(SelfCustodyError::MuSig(_))));
+    }
+
+    #[test]
+    fn test_musig_nonce_gen() {
+        // BIP-327 nonce_gen_vectors.json
+        let bytes = |v: &str| hex::decode(v).unwrap();
+        let secret = SecretKey::from_slice(&[2; 32]).unwrap();
+        let public_key = PublicKey::from_str(
+            "024D4B6CD1361032CA9BD2AEB9D900AA4D45D9EAD80AC9423374C451A7254D0766",
+        )
+        .unwrap();
+        assert_eq!(secret.public_key(&Secp256k1::new()), public_key);
+        let msg = bytes(&"26".repeat(38));
+        let cases: [(Option<&[u8]>, &str, &str); 3] = [
+            (
+                Some(&[1; 32]),
+                "B114E502BEAA4E301DD08A50264172C84E41650E6CB726B410C0694D59EFFB64\
+                 95B5CAF28D045B973D63E3C99A44B807BDE375FD6CB39E46DC4A511708D0E9D2",
+                "02F7BE7089E8376EB355272368766B17E88E7DB72047D05E56AA881EA52B3B35DF\
+                 02C29C8046FDD0DED4C7E55869137200FBDBFE2EB654267B6D7013602CAED3115A",
+            ),
+            (
+                Some(&[]),
+                "E862B068500320088138468D47E0E6F147E01B6024244AE45EAC40ACE5929B9F\
+                 0789E051170B9E705D0B9EB49049A323BBBBB206D8E05C19F46C6228742AA7A9",
+                "023034FA5E2679F01EE66E12225882A7A48CC66719B1B9D3B6C4DBD743EFEDA2C5\
+                 03F3FD6F01EB3A8E9CB315D73F1F3D287CAFBB44AB321153C6287F407600205109",
+            ),
+            (
+                Some(&msg),
+                "3221975ACBDEA6820EABF02A02B7F27D3A8EF68EE42787B88CBEFD9AA06AF363\
+                 2EE85B1A61D8EF31126D4663A00DD96E9D1D4959E72D70FE5EBB6E7696EBA66F",
+                "02E5BBC21C69270F59BD634FCBFA281BE9D76601295345112C58954625BF23793A\
+                 021307511C79F95D38ACACFF1B4DA98228B77E65AA216AD075E9673286EFB4EAF3",
+            ),
+        ];
+        for (msg, expected_secnonce, expected_pubnonce) in cases {
+            let (sec_nonce, pub_nonce) =
+                nonce_gen([15; 32], Some(&secret), public_key, Some(&[7; 32]), msg, &[8; 32])
+                    .unwrap();
+            assert_eq!(sec_nonce.serialize()[..64], bytes(expected_secnonce));
+            assert_eq!(sec_nonce.serialize()[64..], public_key.serialize());
+            assert_eq!(pub_nonce.serialize().to_vec(), bytes(expected_pubnonce));
+        }
+
+        // every optional input left out
+        let public_key = PublicKey::from_str(
+            "02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9",
+        )
+        .unwrap();
+        let (sec_nonce, pub_nonce) =
+            nonce_gen([15; 32], None, public_key, None, None, &[]).unwrap();
+        assert_eq!(
+            sec_nonce.serialize()[..64],
+            bytes(
+                "89BDD787D0284E5E4D5FC572E49E316BAB7E21E3B1830DE37DFE80156FA41A6D\
+                 0B17AE8D024C53679699A6FD7944D9C4A366B514BAF43088E0708B1023DD2897"
+            )
+        );
+        assert_eq!(
+            pub_nonce.serialize().to_vec(),
+            bytes(
+                "02C96E7CB1E8AA5DAC64D872947914198F607D90ECDE5200DE52978AD5DED63C00\
+                 0299EC5117C2D29EDEE8A2092587C3909BE694D5CFF0667D6C02EA4059F7CD9786"
+            )
+        );
+    }
+
+    #[test]
+    fn test_musig_sign_verify() {
+        // BIP-327 sign_verify_vectors.json, the sessions only sign 32-byte messages
+        let bytes = |v: &str| hex::decode(v).unwrap();
+        let secret =
+            SecretKey::from_str("7FB9E0E687ADA1EEBF7ECFE2F21E73EBDB51A7D450948DFE8D76D7F2D1007671")
+                .unwrap();
+        let keys: Vec<PublicKey> = [
+            "03935F972DA013F80AE011890FA89B67A27B7BE6CCB24D3274D18B2D4067F261A9",
+            "02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9",
+            "02DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA661",
+        ]
+        .iter()
+        .map(|v| PublicKey::from_str(v).unwrap())
+        .collect();
+        let sec_nonce: [u8; 97] = bytes(
+            "508B81A611F100A6B2B6B29656590898AF488BCF2E1F55CF22E5CFB84421FE61\
+             FA27FD49B1D50085B481285E1CA205D55C82CC1B31FF5CD54A489829355901F7\
+             03935F972DA013F80AE011890FA89B67A27B7BE6CCB24D3274D18B2D4067F261A9",
+        )
+        .try_into()
+        .unwrap();
+        let pub_nonces: Vec<PubNonce> = [
+            "0337C87821AFD50A8644D820A8F3E02E499C931865C2360FB43D0A0D20DAFE07EA\
+             0287BF891D2A6DEAEBADC909352AA9405D1428C15F4B75F04DAE642A95C2548480",
+            "0279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798\
+             0279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798",
+            "032DE2662628C90B03F5E720284EB52FF7D71F4284F627B68A853D78C78E1FFE93\
+             03E4C5524E83FFE1493B9077CF1CA6BEB2090C93D930321071AD40B2F44E599046",
+            "0237C87821AFD50A8644D820A8F3E02E499C931865C2360FB43D0A0D20DAFE07EA\
+             0387BF891D2A6DEAEBADC909352AA9405D1428C15F4B75F04DAE642A95C2548480",
+        ]
+        .iter()
+        .map(|v| PubNonce::from_slice(&bytes(v)).unwrap())
+        .collect();
+        let msg: [u8; 32] =
+            bytes("F95466D086770E689964664219266FE5ED215C92AE20BAB5C9D79ADDDDF3C0CF")
+                .try_into()
+                .unwrap();
+
+        let session_of = |key_indices: &[usize], nonce_indices: &[usize]| {
+            let ctx = KeyAggContext::new(key_indices.iter().map(|i| keys[*i]).collect()).unwrap();
+            let nonces: Vec<PubNonce> = nonce_indices.iter().map(|i| pub_nonces[*i]).collect();
+            (ctx, AggNonce::new(&nonces).unwrap())
+        };
+        let (_, agg_nonce) = session_of(&[0], &[0, 1, 2]);
+        assert_eq!(
+            agg_nonce.serialize().to_vec(),
+            bytes(
+                "028465FCF0BBDBCF443AABCCE533D42B4B5A10966AC09A49655E8C42DAAB8FCD61\
+                 037496A3CC86926D452CAFCFD55D25972CA1675D549310DE296BFF42F72EEEA8C9"
+            )
+        );
+        // both halves of the aggregate nonce are infinity
+        assert_eq!(session_of(&[0], &[0, 3]).1.serialize(), [0; 66]);
+
+        let cases: [(&[usize], &[usize], usize, &str); 4] = [
+            (
+                &[0, 1, 2],
+                &[0, 1, 2],
+                0,
+                "012ABBCB52B3016AC03AD82395A1A415C48B93DEF78718E62A7A90052FE224FB",
+            ),
+            (
+                &[1, 0, 2],
+                &[1, 0, 2],
+                1,
+                "9FF2F7AAA856150CC8819254218D3ADEEB0535269051897724F9DB3789513A52",
+            ),
+            (
+                &[1, 2, 0],
+                &[1, 2, 0],
+                2,
+                "FA23C359F6FAC4E7796BB93BC9F0532A95468C539BA20FF86D7C76ED92227900",
+            ),
+            (
+                &[0, 1],
+                &[0, 3],
+                0,
+                "AE386064B26105404798F75DE2EB9AF5EDA5387B064B83D049CB7C5E08879531",
+            ),
+        ];
+        for (key_indices, nonce_indices, signer, expected) in cases {
+            let (ctx, agg_nonce) = session_of(key_indices, nonce_indices);
+            let session = Session::new(&ctx, &agg_nonce, &msg).unwrap();
+            let psig =
+                session.partial_sign(SecNonce::from_slice(&sec_nonce).unwrap(), &secret).unwrap();
+            assert_eq!(psig.serialize().to_vec(), bytes(expected));
+            let pub_nonce = &pub_nonces[nonce_indices[signer]];
+            session.verify_partial(&psig, pub_nonce, &keys[key_indices[signer]]).unwrap();
+        }
+
+        let (ctx, agg_nonce) = session_of(&[0, 1, 2], &[0, 1, 2]);
+        let session = Session::new(&ctx, &agg_nonce, &msg).unwrap();
+        let psig = |v: &str| PartialSignature::from_slice(&bytes(v));
+        // the negation of the valid signature
+        let negated = psig("FED54434AD4CFE953FC527DC6A5E5BE8F6234907B7C187559557CE87A0541C46");
+        assert!(session.verify_partial(&negated.unwrap(), &pub_nonces[0], &keys[0]).is_err());
+        // the valid signature checked against another signer
+        let valid = psig("012ABBCB52B3016AC03AD82395A1A415C48B93DEF78718E62A7A90052FE224FB");
+        assert!(session.verify_partial(&valid.unwrap(), &pub_nonces[1], &keys[1]).is_err());
+        assert!(psig("FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFEBAAEDCE6AF48A03BBFD25E8CD0364141").is_err());
+
+        // an invalid public key or public nonce, a signer outside the keys and a used nonce
+        assert!(PublicKey::from_str(
+            "020000000000000000000000000000000000000000000000000000000000000007"
+        )
+        .is_err());
+        assert!(PubNonce::from_slice(&bytes(
+            "0200000000000000000000000000000000000000000000000000000000000000090287BF891D2A6DEAEBA\
+             DC909352AA9405D1428C15F4B75F04DAE642A95C2548480"
+        ))
+        .is_err());
+        let (ctx, agg_nonce) = session_of(&[1, 2], &[0, 1, 2]);
+        let session = Session::new(&ctx, &agg_nonce, &msg).unwrap();
+        assert!(matches!(
+            session.partial_sign(SecNonce::from_slice(&sec_nonce).unwrap(), &secret),
+            Err(SelfCustodyError::MuSig(_))
+        ));
+        let mut used = sec_nonce;
+        used[..64].copy_from_slice(&[0; 64]);
+        assert!(SecNonce::from_slice(&used).is_err());
+    }
+
+    #[test]
+    fn test_musig_tweak() {
+        // BIP-327 tweak_vectors.json
+        let bytes = |v: &str| hex::decode(v).unwrap();
+        let secret =
+            SecretKey::from_str("7FB9E0E687ADA1EEBF7ECFE2F21E73EBDB51A7D450948DFE8D76D7F2D1007671")
+                .unwrap();
+        let keys: Vec<PublicKey> = [
+            "03935F972DA013F80AE011890FA89B67A27B7BE6CCB24D3274D18B2D4067F261A9",
+            "02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9",
+            "02DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+        ]
+        .iter()
+        .map(|v| PublicKey::from_str(v).unwrap())
+        .collect();
+        let sec_nonce: [u8; 97] = bytes(
+            "508B81A611F100A6B2B6B29656590898AF488BCF2E1F55CF22E5CFB84421FE61\
+             FA27FD49B1D50085B481285E1CA205D55C82CC1B31FF5CD54A489829355901F7\
+             03935F972DA013F80AE011890FA89B67A27B7BE6CCB24D3274D18B2D4067F261A9",
+        )
+        .try_into()
+        .unwrap();
+        let pub_nonces: Vec<PubNonce> = [
+            "0337C87821AFD50A8644D820A8F3E02E499C931865C2360FB43D0A0D20DAFE07EA\
+             0287BF891D2A6DEAEBADC909352AA9405D1428C15F4B75F04DAE642A95C2548480",
+            "0279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798\
+             0279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798",
+            "032DE2662628C90B03F5E720284EB52FF7D71F4284F627B68A853D78C78E1FFE93\
+             03E4C5524E83FFE1493B9077CF1CA6BEB2090C93D930321071AD40B2F44E599046",
+        ]
+        .iter()
+        .map(|v| PubNonce::from_slice(&bytes(v)).unwrap())
+        .collect();
+        let tweaks: Vec<[u8; 32]> = [
+            "E8F791FF9225A2AF0102AFFF4A9A723D9612A682A25EBE79802B263CDFCD83BB",
+            "AE2EA797CC0FE72AC5B97B97F3C6957D7E4199A167A58EB08BCAFFDA70AC0455",
+            "F52ECBC565B3D8BEA2DFD5B75A4F457E54369809322E4120831626F290FA87E0",
+            "1969AD73CC177FA0B4FCED6DF1F7BF9907E665FDE9BA196A74FED0A3CF5AEF9D",
+            "FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFEBAAEDCE6AF48A03BBFD25E8CD0364141",
+        ]
+        .iter()
+        .map(|v| bytes(v).try_into().unwrap())
+        .collect();
+        let msg: [u8; 32] =
+            bytes("F95466D086770E689964664219266FE5ED215C92AE20BAB5C9D79ADDDDF3C0CF")
+                .try_into()
+                .unwrap();
+
+        // the signer is the last of the keys 1, 2, 0 with the nonces in the same order
+        let ctx = KeyAggContext::new(vec![keys[1], keys[2], keys[0]]).unwrap();
+        let nonces = [pub_nonces[1], pub_nonces[2], pub_nonces[0]];
+        let agg_nonce = AggNonce::new(&nonces).unwrap();
+        let cases: [(&[(usize, bool)], &str); 5] = [
+            (&[(0, true)], "E28A5C66E61E178C2BA19DB77B6CF9F7E2F0F56C17918CD13135E60CC848FE91"),
+            (&[(0, false)], "38B0767798252F21BF5702C48028B095428320F73A4B14DB1E25DE58543D2D2D"),
+            (
+                &[(0, false), (1, true)],
+                "408A0A21C4A0F5DACAF9646AD6EB6FECD7F7A11F03ED1F48DFFF2185BC2C2408",
+            ),
+            (
+                &[(0, false), (1, false), (2, true), (3, true)],
+                "45ABD206E61E3DF2EC9E264A6FEC8292141A633C28586388235541F9ADE75435",
+            ),
+            (
+                &[(0, true), (1, false), (2, true), (3, false)],
+                "B255FDCAC27B40C7CE7848E2D3B7BF5EA0ED756DA81565AC804CCCA3E1D5D239",
+            ),
+        ];
+        for (tweak_indices, expected) in cases {
+            let ctx = tweak_indices
+                .iter()
+                .try_fold(ctx.clone(), |ctx, (i, is_xonly)| ctx.with_tweak(&tweaks[*i], *is_xonly))
+                .unwrap();
+            let session = Session::new(&ctx, &agg_nonce, &msg).unwrap();
+            let psig =
+                session.partial_sign(SecNonce::from_slice(&sec_nonce).unwrap(), &secret).unwrap();
+            assert_eq!(psig.serialize().to_vec(), bytes(expected));
+            session.verify_partial(&psig, &nonces[2], &keys[0]).unwrap();
+        }
+
+        // a tweak exceeding the curve order
+        assert!(matches!(ctx.with_tweak(&tweaks[4], false), Err(SelfCustodyError::MuSig(_))));
+    }
+
+    #[test]
+    fn test_musig_sig_agg() {
+        // BIP-327 sig_agg_vectors.json
+        let bytes = |v: &str| hex::decode(v).unwrap();
+        let keys: Vec<PublicKey> = [
+            "03935F972DA013F80AE011890FA89B67A27B7BE6CCB24D3274D18B2D4067F261A9",
+            "02D2DC6F5DF7C56ACF38C7FA0AE7A759AE30E19B37359DFDE015872324C7EF6E05",
+            "03C7FB101D97FF930ACD0C6760852EF64E69083DE0B06AC6335724754BB4B0522C",
+            "02352433B21E7E05D3B452B81CAE566E06D2E003ECE16D1074AABA4289E0E3D581",
+        ]
+        .iter()
+        .map(|v| PublicKey::from_str(v).unwrap())
+        .collect();
+        let pub_nonces: Vec<PubNonce> = [
+            "036E5EE6E28824029FEA3E8A9DDD2C8483F5AF98F7177C3AF3CB6F47CAF8D94AE9\
+             02DBA67E4A1F3680826172DA15AFB1A8CA85C7C5CC88900905C8DC8C328511B53E",
+            "03E4F798DA48A76EEC1C9CC5AB7A880FFBA201A5F064E627EC9CB0031D1D58FC51\
+             03E06180315C5A522B7EC7C08B69DCD721C313C940819296D0A7AB8E8795AC1F00",
+            "02C0068FD25523A31578B8077F24F78F5BD5F2422AFF47C1FADA0F36B3CEB6C7D2\
+             02098A55D1736AA5FCC21CF0729CCE852575C06C081125144763C2C4C4A05C09B6",
+            "031F5C87DCFBFCF330DEE4311D85E8F1DEA01D87A6F1C14CDFC7E4F1D8C441CFA4\
+             0277BF176E9F747C34F81B0D9F072B1B404A86F402C2D86CF9EA9E9C69876EA3B9",
+            "023F7042046E0397822C4144A17F8B63D78748696A46C3B9F0A901D296EC3406C3\
+             02022B0B464292CF9751D699F10980AC764E6F671EFCA15069BBE62B0D1C62522A",
+        ]
+        .iter()
+        .map(|v| PubNonce::from_slice(&bytes(v)).unwrap())
+        .collect();
+        let tweaks: Vec<[u8; 32]> = [
+            "B511DA492182A91B0FFB9A98020D55F260AE86D7ECBD0399C7383D59A5F2AF7C",
+            "A815FE049EE3C5AAB66310477FBC8BCCCAC2F3395F59F921C364ACD78A2F48DC",
+            "75448A87274B056468B977BE06EB1E9F657577B7320B0A3376EA51FD420D18A8",
+        ]
+        .iter()
+        .map(|v| bytes(v).try_into().unwrap())
+        .collect();
+        let psigs: Vec<PartialSignature> = [
+            "B15D2CD3C3D22B04DAE438CE653F6B4ECF042F42CFDED7C41B64AAF9B4AF53FB",
+            "6193D6AC61B354E9105BBDC8937A3454A6D705B6D57322A5A472A02CE99FCB64",
+            "9A87D3B79EC67228CB97878B76049B15DBD05B8158D17B5B9114D3C226887505",
+            "66F82EA90923689B855D36C6B7E032FB9970301481B99E01CDB4D6AC7C347A15",
+            "4F5AEE41510848A6447DCD1BBC78457EF69024944C87F40250D3EF2C25D33EFE",
+            "DDEF427BBB847CC027BEFF4EDB01038148917832253EBC355FC33F4A8E2FCCE4",
+            "97B890A26C981DA8102D3BC294159D171D72810FDF7C6A691DEF02F0F7AF3FDC",
+            "53FA9E08BA5243CBCB0D797C5EE83BC6728E539EB76C2D0BF0F971EE4E909971",
+        ]
+        .iter()
+        .map(|v| PartialSignature::from_slice(&bytes(v)).unwrap())
+        .collect();
+        let msg: [u8; 32] =
+            bytes("599C67EA410D005B9DA90817CF03ED3B1C868E4DA4EDF00A5880B0082C237869")
+                .try_into()
+                .unwrap();
+
+        type Case<'a> = (&'a str, [usize; 2], [usize; 2], &'a [(usize, bool)], [usize; 2], &'a str);
+        let cases: [Case; 4] = [
+            (
+                "0341432722C5CD0268D829C702CF0D1CBCE57033EED201FD335191385227C3210C\
+                 03D377F2D258B64AADC0E16F26462323D701D286046A2EA93365656AFD9875982B",
+                [0, 1],
+                [0, 1],
+                &[],
+                [0, 1],
+                "041DA22223CE65C92C9A0D6C2CAC828AAF1EEE56304FEC371DDF91EBB2B9EF09\
+                 12F1038025857FEDEB3FF696F8B99FA4BB2C5812F6095A2E0004EC99CE18DE1E",
+            ),
+            (
+                "0224AFD36C902084058B51B5D36676BBA4DC97C775873768E58822F87FE437D792\
+                 028CB15929099EEE2F5DAE404CD39357591BA32E9AF4E162B8D3E7CB5EFE31CB20",
+                [0, 2],
+                [0, 2],
+                &[],
+                [2, 3],
+                "1069B67EC3D2F3C7C08291ACCB17A9C9B8F2819A52EB5DF8726E17E7D6B52E9F\
+                 01800260A7E9DAC450F4BE522DE4CE12BA91AEAF2B4279219EF74BE1D286ADD9",
+            ),
+            (
+                "0208C5C438C710F4F96A61E9FF3C37758814B8C3AE12BFEA0ED2C87FF6954FF186\
+                 020B1816EA104B4FCA2D304D733E0E19CEAD51303FF6420BFD222335CAA402916D",
+                [0, 3],
+                [0, 2],
+                &[(0, false)],
+                [4, 5],
+                "5C558E1DCADE86DA0B2F02626A512E30A22CF5255CAEA7EE32C38E9A71A0E914\
+                 8BA6C0E6EC7683B64220F0298696F1B878CD47B107B81F7188812D593971E0CC",
+            ),
+            (
+                "02B5AD07AFCD99B6D92CB433FBD2A28FDEB98EAE2EB09B6014EF0F8197CD584033\
+                 02E8616910F9293CF692C49F351DB86B25E352901F0E237BAFDA11F1C1CEF29FFD",
+                [0, 4],
+                [0, 3],
+                &[(0, true), (1, false), (2, true)],
+                [6, 7],
+                "839B08820B681DBA8DAF4CC7B104E8F2638F9388F8D7A555DC17B6E6971D7426\
+                 CE07BF6AB01F1DB50E4E33719295F4094572B79868E440FB3DEFD3FAC1DB589E",
+            ),
+        ];
+        let secp = Secp256k1::verification_only();
+        for (agg_nonce_hex, nonces, key_indices, tweak_indices, psig_indices, expected) in cases {
+            let agg_nonce = AggNonce::new(&nonces.map(|i| pub_nonces[i])).unwrap();
+            assert_eq!(agg_nonce.serialize().to_vec(), bytes(agg_nonce_hex));
+            let ctx = tweak_indices
+                .iter()
+                .try_fold(
+                    KeyAggContext::new(key_indices.map(|i| keys[i]).to_vec()).unwrap(),
+                    |ctx, (i, is_xonly)| ctx.with_tweak(&tweaks[*i], *is_xonly),
+                )
+                .unwrap();
+            let session = Session::new(&ctx, &agg_nonce, &msg).unwrap();
+            let sig = session.aggregate(&psig_indices.map(|i| psigs[i].clone())).unwrap();
+            assert_eq!(sig.as_ref().to_vec(), bytes(expected));
+            let msg = bitcoin::secp256k1::Message::from_digest(msg);
+            secp.verify_schnorr(&sig, &msg, &ctx.aggregated_key()).unwrap();
+        }
+
+        // a partial signature exceeding the curve order
+        assert!(PartialSignature::from_slice(&bytes(
+            "FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFEBAAEDCE6AF48A03BBFD25E8CD0364141"
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_spent_with_nums_internal_key() {
+        let secp = Secp256k1::new();
+        let keypair1 = Keypair::new(&secp, &mut rand::thread_rng());
+        let keypair2 = Keypair::new(&secp, &mut rand::thread_rng());
+        let s1 = keypair1.secret_bytes().to_vec();
+        let s2 = keypair2.secret_bytes().to_vec();
+        let (a1, a2, _, _) = create_account(s1.clone(), s2.clone());
+
+        // H itself and the randomized H + rG, proven by revealing r
+        let h = NumsKey::new(None).unwrap();
+        assert_eq!(h.public_key(), XOnlyPublicKey::from_str(NUMS_POINT).unwrap());
+        let nums = NumsKey::random();
+        assert_ne!(nums.public_key(), h.public_key());
+        assert!(NumsKey::verify(nums.public_key(), nums.randomizer()));
+        assert!(!NumsKey::verify(nums.public_key(), None));
+        assert!(matches!(
+            NumsKey::new(Some([0xff; 32])),
+            Err(SelfCustodyError::InvalidKey(_))
+        ));
+
+        let policy = CustodyPolicy::new(
+            nums,
+            build_mulsig2(a1, a2).into_script(),
+            build_enforce(100, a1).into_script(),
+            build_escape(110, a2).into_script(),
+            Network::Regtest,
+        );
+        assert!(!policy.has_key_path());
+        assert_eq!(policy.nums_key(), Some(nums));
+        assert_eq!(policy.internal_key(), nums.public_key());
+
+        let out_points: Vec<LocalUtxo> = vec![LocalUtxo {
+            txid: "0b78bd9e57b99e83bb1b5f1a1c1ecd8ae801fcb62f41cb62f986b38090354b65".to_string(),
+            vout: 1,
+            amount: 100000000,
+            script_pubkey: None,
+            height: None,
+        }];
+        let receiver =
+            Address::from_str("bcrt1pz7y5ps533cnjg8vhgjct6zt4zta8pc9tym6j39v52c37rj8tce7qzrzxj5")
+                .unwrap()
+                .assume_checked();
+
+        // no key path to spend through
+        let err = build_tx(
+            SpendType::KEY,
+            std::slice::from_ref(&policy),
+            out_points.clone(),
+            sat_vb(1),
+            &Payment::to(receiver.clone(), Amount::from_sat(10000000)),
+        )
+        .unwrap_err();
+        assert!(matches!(err, SelfCustodyError::InvalidUtxo(_)));
+
+        // the leaves are spent as usual
+        let mut psbt = build_tx(
+            SpendType::MULSIG,
+            &[policy],
+            out_points,
+            sat_vb(1),
+            &Payment::to(receiver, Amount::from_sat(10000000)),
+        )
+        .unwrap();
+        sign_psbt(&mut psbt, &s1).unwrap();
+        sign_psbt(&mut psbt, &s2).unwrap();
+        let prevouts = psbt_prevouts(&psbt);
+        let tx = finalize_psbt(psbt).unwrap();
+        verify_transaction(&tx, &prevouts).unwrap();
+    }
+
+    #[test]
+    fn test_coin_selection() {
+        let secp = Secp256k1::new();
+        let keypair1 = Keypair::new(&secp, &mut rand::thread_rng());
+        let keypair2 = Keypair::new(&secp, &mut rand::thread_rng());
+        let (a1, a2, sum, _) = create_account(
+            keypair1.secret_bytes().to_vec(),
+            keypair2.secret_bytes().to_vec(),
+        );
+        let policy = CustodyPolicy::new(
+            sum,
+            build_mulsig2(a1, a2).into_script(),
+            build_enforce(100, a1).into_script(),
+            build_escape(110, a2).into_script(),
+            Network::Regtest,
+        );
+        let policies = [policy];
+        let utxo = |vout: u32, amount: u64, height: Option<u32>| LocalUtxo {
+            txid: "0b78bd9e57b99e83bb1b5f1a1c1ecd8ae801fcb62f41cb62f986b38090354b65".to_string(),
+            vout,
+            amount,
+            script_pubkey: None,
+            height,
+        };
+        let utxos = vec![
+            utxo(0, 50_000_000, None),
+            utxo(1, 30_000_000, Some(300)),
+            utxo(2, 10_000_000, Some(200)),
+            utxo(3, 2_000_000, Some(100)),
+        ];
+        let receiver =
+            Address::from_str("bcrt1pz7y5ps533cnjg8vhgjct6zt4zta8pc9tym6j39v52c37rj8tce7qzrzxj5")
+                .unwrap()
+                .assume_checked();
+        let fee_rate = sat_vb(1);
+        let select = |amount: u64, fallback: CoinSelectionAlgorithm| {
+            select_utxos(
+                SpendType::MULSIG,
+                &policies,
+                utxos.clone(),
+                fee_rate,
+                &Payment::to(receiver.clone(), Amount::from_sat(amount)),
+                fallback,
+            )
+        };
+
+        // the fee of spending utxos 2 and 3, leaving 100 sat below the dust of the change
+        let psbt = build_tx(
+            SpendType::MULSIG,
+            &policies,
+            utxos[2..].to_vec(),
+            fee_rate,
+            &Payment::to(receiver.clone(), Amount::from_sat(1_000_000)),
+        )
+        .unwrap();
+        let outputs: u64 = psbt.unsigned_tx.output.iter().map(|v| v.value.to_sat()).sum();
+        let fee = 12_000_000 - outputs;
+        let amount = 12_000_000 - fee - 100;
+
+        let selection = select(amount, CoinSelectionAlgorithm::BranchAndBound).unwrap();
+        assert_eq!(selection.algorithm, CoinSelectionAlgorithm::BranchAndBound);
+        assert_eq!(selection.fee, fee);
+        let selected: Vec<u32> = selection.selected.iter().map(|v| v.vout).collect();
+        let unselected: Vec<u32> = selection.unselected.iter().map(|v| v.vout).collect();
+        assert_eq!(selected, vec![2, 3]);
+        assert_eq!(unselected, vec![0, 1]);
+        let psbt = build_tx(
+            SpendType::MULSIG,
+            &policies,
+            selection.selected,
+            fee_rate,
+            &Payment::to(receiver.clone(), Amount::from_sat(amount)),
+        )
+        .unwrap();
+        assert_eq!(psbt.unsigned_tx.output.len(), 1);
+
+        // no changeless solution
+        let err = select(35_000_000, CoinSelectionAlgorithm::BranchAndBound).unwrap_err();
+        assert!(matches!(err, SelfCustodyError::InvalidUtxo(_)));
+        let selection = select(35_000_000, CoinSelectionAlgorithm::LargestFirst).unwrap();
+        assert_eq!(selection.algorithm, CoinSelectionAlgorithm::LargestFirst);
+        let selected: Vec<u32> = selection.selected.iter().map(|v| v.vout).collect();
+        assert_eq!(selected, vec![0]);
+        assert_eq!(selection.unselected.len(), 3);
+        let selection = select(35_000_000, CoinSelectionAlgorithm::OldestFirst).unwrap();
+        let selected: Vec<u32> = selection.selected.iter().map(|v| v.vout).collect();
+        let unselected: Vec<u32> = selection.unselected.iter().map(|v| v.vout).collect();
+        assert_eq!(selected, vec![1, 2, 3]);
+        assert_eq!(unselected, vec![0]);
+
+        let err = select(100_000_000, CoinSelectionAlgorithm::LargestFirst).unwrap_err();
+        assert!(matches!(err, SelfCustodyError::InsufficientFunds { .. }));
+    }
+
+    #[test]
+    fn test_payment_outputs() {
+        let secp = Secp256k1::new();
+        let keypair1 = Keypair::new(&secp, &mut rand::thread_rng());
+        let keypair2 = Keypair::new(&secp, &mut rand::thread_rng());
+        let s1 = keypair1.secret_bytes().to_vec();
+        let s2 = keypair2.secret_bytes().to_vec();
+        let (a1, a2, sum, _) = create_account(s1.clone(), s2.clone());
+        let custody = |escape_time: u32| {
+            CustodyPolicy::new(
+                sum,
+                build_mulsig2(a1, a2).into_script(),
+                build_enforce(100, a1).into_script(),
+                build_escape(escape_time, a2).into_script(),
+                Network::Regtest,
+            )
+        };
+        let policies = [custody(110)];
+        // a fresh custody address with a later escape time
+        let fresh = custody(210).address();
+
+        let out_points: Vec<LocalUtxo> = vec![LocalUtxo {
+            txid: "0b78bd9e57b99e83bb1b5f1a1c1ecd8ae801fcb62f41cb62f986b38090354b65".to_string(),
+            vout: 1,
+            amount: 100000000,
+            script_pubkey: None,
+            height: None,
+        }];
+        let receiver1 =
+            Address::from_str("bcrt1pz7y5ps533cnjg8vhgjct6zt4zta8pc9tym6j39v52c37rj8tce7qzrzxj5")
+                .unwrap()
+                .assume_checked();
+        let receiver2 = custody(310).address();
+        let recipients = vec![
+            (receiver1.clone(), Amount::from_sat(30000000)),
+            (receiver2.clone(), Amount::from_sat(20000000)),
+        ];
+        let build = |payment: &Payment| {
+            build_tx(SpendType::MULSIG, &policies, out_points.clone(), sat_vb(1), payment)
+        };
+
+        let payment = Payment::new(recipients.clone()).with_change(fresh.clone());
+        let psbt = build(&payment).unwrap();
+        let outputs = psbt.unsigned_tx.output.clone();
+        assert_eq!(outputs.len(), 3);
+        assert_eq!(outputs[0].script_pubkey, receiver1.script_pubkey());
+        assert_eq!(outputs[1].value, Amount::from_sat(20000000));
+        assert_eq!(outputs[2].script_pubkey, fresh.script_pubkey());
+
+        // BIP-69 sorts by amount then script, the random order keeps the same outputs
+        let psbt = build(&payment.clone().with_ordering(OutputOrdering::Bip69)).unwrap();
+        let mut sorted = outputs.clone();
+        sorted.sort_by_key(|v| (v.value, v.script_pubkey.clone()));
+        assert_eq!(psbt.unsigned_tx.output, sorted);
+        assert_eq!(psbt.unsigned_tx.output[0].value, Amount::from_sat(20000000));
+        let psbt = build(&payment.with_ordering(OutputOrdering::Random)).unwrap();
+        let mut shuffled = psbt.unsigned_tx.output.clone();
+        shuffled.sort_by_key(|v| (v.value, v.script_pubkey.clone()));
+        assert_eq!(shuffled, sorted);
+
+        // the last recipient takes everything left after the fee
+        let mut psbt = build(&Payment::new(recipients.clone()).with_sweep()).unwrap();
+        let outputs = &psbt.unsigned_tx.output;
+        assert_eq!(outputs.len(), 2);
+        assert_eq!(outputs[0].value, Amount::from_sat(30000000));
+        assert_eq!(outputs[1].script_pubkey, receiver2.script_pubkey());
+        assert!(outputs[1].value > Amount::from_sat(69990000));
+        sign_psbt(&mut psbt, &s1).unwrap();
+        sign_psbt(&mut psbt, &s2).unwrap();
+        let prevouts = psbt_prevouts(&psbt);
+        let tx = finalize_psbt(psbt).unwrap();
+        verify_transaction(&tx, &prevouts).unwrap();
+        let psbt = build(&Payment::sweep(receiver1.clone())).unwrap();
+        assert_eq!(psbt.unsigned_tx.output.len(), 1);
+
+        // the swept recipient is never dropped as dust
+        let payment = Payment::new(vec![
+            (receiver1.clone(), Amount::from_sat(99999600)),
+            (receiver2.clone(), Amount::ZERO),
+        ])
+        .with_sweep();
+        let SelfCustodyError::InsufficientFunds { available, needed } = build(&payment).unwrap_err()
+        else {
+            panic!("the swept recipient is below dust");
+        };
+        // the other recipients are owed too
+        assert!(needed > 99999600 + receiver2.script_pubkey().dust_value().to_sat());
+        assert!(needed > available);
+        assert!(matches!(
+            build(&Payment::new(vec![])).unwrap_err(),
+            SelfCustodyError::InvalidAddress(_)
+        ));
+        let err = select_utxos(
+            SpendType::MULSIG,
+            &policies,
+            out_points.clone(),
+            sat_vb(1),
+            &Payment::sweep(receiver1),
+            CoinSelectionAlgorithm::LargestFirst,
+        )
+        .unwrap_err();
+        assert!(matches!(err, SelfCustodyError::InvalidUtxo(_)));
+    }
+
+    #[test]
+    fn test_map_op_return_data() {
+        let data =
+            MapOpReturnData::evm(0x1a2b, "0x8d5fa6d2a4b5e3f1b1c7e5a0c5f2e2c2d3f4a5b6").unwrap();
+        let bytes = data.to_bytes();
+        assert_eq!(bytes.len(), 25);
+        assert_eq!(&bytes[..5], &[0x00, 0x00, 0x1a, 0x2b, 20]);
+        assert_eq!(MapOpReturnData::from_bytes(&bytes).unwrap(), data);
+        assert_eq!(data.dst_chain(), 0x1a2b);
+        assert_eq!(data.receiver(), &bytes[5..]);
+
+        let script = data.script();
+        assert!(script.is_op_return());
+        assert_eq!(MapOpReturnData::from_script(&script).unwrap(), data);
+
+        // strict lengths
+        assert!(MapOpReturnData::evm(1, "0x8d5fa6d2a4b5e3f1b1c7e5a0c5f2e2c2d3f4a5").is_err());
+        assert!(MapOpReturnData::evm(1, "0xzz").is_err());
+        assert!(MapOpReturnData::new(1, vec![]).is_err());
+        assert!(MapOpReturnData::new(1, vec![1; MAX_OP_RETURN_DATA - 4]).is_err());
+        assert!(MapOpReturnData::new(1, vec![1; MAX_OP_RETURN_DATA - 5]).is_ok());
+        assert!(MapOpReturnData::from_bytes(&bytes[..24]).is_err());
+    }
+
+    #[test]
+    fn test_build_mapping_tx() {
+        let secp = Secp256k1::new();
+        let keypair1 = Keypair::new(&secp, &mut rand::thread_rng());
+        let keypair2 = Keypair::new(&secp, &mut rand::thread_rng());
+        let (a1, a2, sum, _) = create_account(
+            keypair1.secret_bytes().to_vec(),
+            keypair2.secret_bytes().to_vec(),
+        );
+        let policy = CustodyPolicy::new(
+            sum,
+            build_mulsig2(a1, a2).into_script(),
+            build_enforce(100, a1).into_script(),
+            build_escape(110, a2).into_script(),
+            Network::Regtest,
+        );
+
+        let wallet =
+            Address::from_str("bcrt1pz7y5ps533cnjg8vhgjct6zt4zta8pc9tym6j39v52c37rj8tce7qzrzxj5")
+                .unwrap()
+                .assume_checked();
+        let utxos = vec![LocalUtxo {
+            txid: "0b78bd9e57b99e83bb1b5f1a1c1ecd8ae801fcb62f41cb62f986b38090354b65".to_string(),
+            vout: 1,
+            amount: 100000000,
+            script_pubkey: Some(wallet.script_pubkey().to_hex_string()),
+            height: None,
+        }];
+        let data = MapOpReturnData::evm(1, "0x8d5fa6d2a4b5e3f1b1c7e5a0c5f2e2c2d3f4a5b6").unwrap();
+
+        let psbt = build_mapping_tx(
+            &policy,
+            utxos.clone(),
+            10000000,
+            &data,
+            sat_vb(1),
+            wallet.clone(),
+        )
+        .unwrap();
+        let tx = &psbt.unsigned_tx;
+        assert_eq!(tx.output.len(), 3);
+        assert_eq!(tx.output[0].script_pubkey, policy.script_pubkey());
+        assert_eq!(tx.output[0].value, Amount::from_sat(10000000));
+        assert_eq!(tx.output[1].value, Amount::ZERO);
+        assert_eq!(
+            MapOpReturnData::from_script(&tx.output[1].script_pubkey).unwrap(),
+            data
+        );
+        assert_eq!(tx.output[2].script_pubkey, wallet.script_pubkey());
+        let fee = 100000000 - 10000000 - tx.output[2].value.to_sat();
+        assert!(fee > 0 && fee < 1000);
+        assert!(psbt.inputs[0].witness_utxo.is_some());
+
+        let classification = classify_transaction(tx, &policy);
+        assert_eq!(classification.tx_type, TxType::Mapping);
+        assert_eq!(classification.inputs, vec![None]);
+        assert_eq!(classification.map_data, Some(data.clone()));
+        assert!(classification.violations.is_empty());
+
+        // paying to the mapping script without the OP_RETURN
+        let mut unmapped = tx.clone();
+        unmapped.output.remove(1);
+        let classification = classify_transaction(&unmapped, &policy);
+        assert_eq!(classification.tx_type, TxType::Mapping);
+        assert_eq!(classification.violations.len(), 1);
+        unmapped.output.remove(0);
+        assert_eq!(classify_transaction(&unmapped, &policy).tx_type, TxType::Unknown);
+
+        // the change is dropped below dust
+        let psbt = build_mapping_tx(
+            &policy,
+            utxos.clone(),
+            100000000 - fee - 100,
+            &data,
+            sat_vb(1),
+            wallet.clone(),
+        )
+        .unwrap();
+        assert_eq!(psbt.unsigned_tx.output.len(), 2);
+
+        // wallet utxos must carry their script
+        let mut missing = utxos.clone();
+        missing[0].script_pubkey = None;
+        assert!(matches!(
+            build_mapping_tx(&policy, missing, 10000000, &data, sat_vb(1), wallet.clone()),
+            Err(SelfCustodyError::InvalidUtxo(_))
+        ));
+        assert!(matches!(
+            build_mapping_tx(&policy, utxos.clone(), 100000000, &data, sat_vb(1), wallet.clone()),
+            Err(SelfCustodyError::InsufficientFunds { .. })
+        ));
+        // a mapping output below dust would never relay
+        assert!(matches!(
+            build_mapping_tx(&policy, utxos, 329, &data, sat_vb(1), wallet),
+            Err(SelfCustodyError::InvalidUtxo(_))
+        ));
+    }
+
+    #[test]
+    fn test_spent_with_burn_credential() {
+        let secp = Secp256k1::new();
+        let keypair1 = Keypair::new(&secp, &mut rand::thread_rng());
+        let keypair2 = Keypair::new(&secp, &mut rand::thread_rng());
+        let s1 = keypair1.secret_bytes().to_vec();
+        let s2 = keypair2.secret_bytes().to_vec();
+        let (a1, a2, sum, _) = create_account(s1.clone(), s2.clone());
+        let policy = CustodyPolicy::new(
+            sum,
+            build_mulsig2(a1, a2).into_script(),
+            build_enforce(100, a1).into_script(),
+            build_escape(110, a2).into_script(),
+            Network::Regtest,
+        );
+
+        let out_points: Vec<LocalUtxo> = vec![LocalUtxo {
+            txid: "0b78bd9e57b99e83bb1b5f1a1c1ecd8ae801fcb62f41cb62f986b38090354b65".to_string(),
+            vout: 1,
+            amount: 100000000,
+            script_pubkey: None,
+            height: None,
+        }];
+        let mapper =
+            Address::from_str("bcrt1pz7y5ps533cnjg8vhgjct6zt4zta8pc9tym6j39v52c37rj8tce7qzrzxj5")
+                .unwrap()
+                .assume_checked();
+        let data = BurnOpReturnData::new(vec![7; 32]).unwrap();
+
+        let mut psbt = build_burn_tx(
+            std::slice::from_ref(&policy),
+            out_points,
+            sat_vb(1),
+            mapper.clone(),
+            10000000,
+            &data,
+        )
+        .unwrap();
+        let outputs = &psbt.unsigned_tx.output;
+        assert_eq!(outputs.len(), 3);
+        assert_eq!(outputs[0].script_pubkey, mapper.script_pubkey());
+        assert_eq!(outputs[0].value, Amount::from_sat(10000000));
+        assert_eq!(outputs[1].value, Amount::ZERO);
+        assert_eq!(BurnOpReturnData::from_script(&outputs[1].script_pubkey).unwrap(), data);
+        assert_eq!(data.uid(), &[7; 32]);
+        assert_eq!(outputs[2].script_pubkey, policy.script_pubkey());
+
+        // co-signed by the mapper and the committee
+        let mut psbt2 = Psbt::deserialize(&psbt.serialize()).unwrap();
+        sign_psbt(&mut psbt, &s1).unwrap();
+        sign_psbt(&mut psbt2, &s2).unwrap();
+        psbt.combine(psbt2).unwrap();
+
+        let prevouts = psbt_prevouts(&psbt);
+        let tx = finalize_psbt(psbt).unwrap();
+        verify_transaction(&tx, &prevouts).unwrap();
+        let classification = classify_transaction(&tx, &policy);
+        assert_eq!(classification.tx_type, TxType::Burning);
+        assert_eq!(classification.inputs, vec![Some(Leaf::Mulsig)]);
+        assert_eq!(classification.burn_data, Some(data));
+        assert!(classification.violations.is_empty());
+
+        assert!(BurnOpReturnData::new(vec![]).is_err());
+        assert!(BurnOpReturnData::new(vec![1; MAX_OP_RETURN_DATA + 1]).is_err());
+    }
+
+    #[test]
+    fn test_forced_withdrawal() {
+        let secp = Secp256k1::new();
+        let keypair1 = Keypair::new(&secp, &mut rand::thread_rng());
+        let keypair2 = Keypair::new(&secp, &mut rand::thread_rng());
+        let s1 = keypair1.secret_bytes().to_vec();
+        let s2 = keypair2.secret_bytes().to_vec();
+        let (a1, a2, sum, _) = create_account(s1.clone(), s2.clone());
+        let new_policy = |escape_time| {
+            CustodyPolicy::new(
+                sum,
+                build_mulsig2(a1, a2).into_script(),
+                build_enforce(100, a1).into_script(),
+                build_escape(escape_time, a2).into_script(),
+                Network::Regtest,
+            )
+        };
+        let policy = new_policy(110);
+        let destination = new_policy(220);
+
+        let out_points: Vec<LocalUtxo> = vec![
+            LocalUtxo {
+                txid: "b88cd14973cab9cd59d7e0e4f9fb36425ec671583fefce8c8d1341a848589ebe"
+                    .to_string(),
+                vout: 1,
+                amount: 50000000,
+                script_pubkey: None,
+                height: None,
+            },
+            LocalUtxo {
+                txid: "0b78bd9e57b99e83bb1b5f1a1c1ecd8ae801fcb62f41cb62f986b38090354b65"
+                    .to_string(),
+                vout: 0,
+                amount: 40000000,
+                script_pubkey: None,
+                height: None,
+            },
+        ];
+
+        let mut psbt = build_forced_withdrawal_tx(
+            std::slice::from_ref(&policy),
+            out_points.clone(),
+            sat_vb(1),
+            100,
+            &destination,
+        )
+        .unwrap();
+        let outputs = &psbt.unsigned_tx.output;
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(outputs[0].script_pubkey, destination.script_pubkey());
+        let fee = 90000000 - outputs[0].value.to_sat();
+        assert!(fee > 0 && fee < 1000);
+
+        // the committee checks the candidate before signing
+        validate_forced_withdrawal(&psbt.unsigned_tx, std::slice::from_ref(&destination))
+            .unwrap();
+        assert!(matches!(
+            validate_forced_withdrawal(&psbt.unsigned_tx, std::slice::from_ref(&policy)),
+            Err(SelfCustodyError::PolicyViolation(_))
+        ));
+        let mut with_op_return = psbt.unsigned_tx.clone();
+        with_op_return.output.push(TxOut {
+            value: Amount::ZERO,
+            script_pubkey: BurnOpReturnData::new(vec![1]).unwrap().script(),
+        });
+        assert!(
+            validate_forced_withdrawal(&with_op_return, std::slice::from_ref(&destination))
+                .is_err()
+        );
+
+        sign_psbt(&mut psbt, &s1).unwrap();
+        let prevouts = psbt_prevouts(&psbt);
+        let tx = finalize_psbt(psbt).unwrap();
+        verify_transaction(&tx, &prevouts).unwrap();
+        let classification = classify_transaction(&tx, &policy);
+        assert_eq!(classification.tx_type, TxType::ForcedWithdrawal);
+        assert!(classification.violations.is_empty());
+
+        // the CLI builds the same transaction, checked against the tip
+        let policies = std::slice::from_ref(&policy);
+        let hex = withdraw_command(policies, 100, sat_vb(1), &destination, out_points.clone(), None)
+            .unwrap();
+        assert_eq!(
+            Psbt::deserialize(&hex::decode(hex).unwrap()).unwrap().unsigned_tx.output,
+            tx.output
+        );
+        let tip = Some(ChainTip {
+            height: 50,
+            median_time_past: 0,
+        });
+        assert!(matches!(
+            withdraw_command(policies, 100, sat_vb(1), &destination, out_points.clone(), tip),
+            Err(SelfCustodyError::LockTimeNotReached { .. })
+        ));
+
+        // nothing left for the single output
+        let mut dust = out_points;
+        dust.truncate(1);
+        dust[0].amount = 400;
+        assert!(matches!(
+            build_forced_withdrawal_tx(&[policy], dust, sat_vb(1), 100, &destination),
+            Err(SelfCustodyError::InsufficientFunds { .. })
+        ));
+    }
+
+    #[test]
+    fn test_spent_from_one_way_channel() {
+        let secp = Secp256k1::new();
+        let committee = Keypair::new(&secp, &mut rand::thread_rng());
+        let validator = Keypair::new(&secp, &mut rand::thread_rng());
+        let committee_xonly = committee.x_only_public_key().0;
+        let escape_time = 220;
+
+        let policy = CustodyPolicy::one_way(
+            committee_xonly,
+            build_committee(committee_xonly).into_script(),
+            build_escape(escape_time, validator.x_only_public_key().0).into_script(),
+            Network::Regtest,
+        );
+        println!("one-way addr: {}", policy.address());
+        assert!(policy.has_leaf(Leaf::Committee));
+        assert!(!policy.has_leaf(Leaf::Mulsig));
+        // a leaf the policy doesn't have is an error, not a panic
+        assert_eq!(policy.script(Leaf::Enforce), Err(SelfCustodyError::MissingLeaf(Leaf::Enforce)));
+        assert!(policy.control_block(Leaf::Mulsig).is_err());
+        assert!(policy.leaf_hash(Leaf::Enforce).is_err());
+        assert!(predict_satisfaction_weight(&policy, Some(Leaf::Enforce)).is_err());
+        let cli_policy = one_way_policy(
+            &committee.public_key().to_string(),
+            &validator.public_key().to_string(),
+            escape_time,
+            None,
+            LeafLock::Absolute,
+            Network::Regtest,
+        )
+        .unwrap();
+        assert_eq!(cli_policy.address(), policy.address());
+        for (leaf, script) in policy.leaves() {
+            let control_block = policy.control_block(*leaf).unwrap();
+            assert_eq!(control_block.merkle_branch.len(), 1);
+            assert!(control_block.verify_taproot_commitment(
+                &secp,
+                policy.spend_info().output_key().to_inner(),
+                script
+            ));
+        }
+
+        let out_points: Vec<LocalUtxo> = vec![LocalUtxo {
+            txid: "0b78bd9e57b99e83bb1b5f1a1c1ecd8ae801fcb62f41cb62f986b38090354b65".to_string(),
+            vout: 1,
+            amount: 100000000,
+            script_pubkey: None,
+            height: None,
+        }];
+        let receiver =
+            Address::from_str("bcrt1pz7y5ps533cnjg8vhgjct6zt4zta8pc9tym6j39v52c37rj8tce7qzrzxj5")
+                .unwrap()
+                .assume_checked();
+
+        for (spent_type, signer, tx_type) in [
+            (SpendType::COMMITTEE, &committee, TxType::Redemption),
+            (SpendType::ESCAPE(escape_time), &validator, TxType::EscapeHatch),
+        ] {
+            let mut psbt = build_tx(
+                spent_type,
+                std::slice::from_ref(&policy),
+                out_points.clone(),
+                sat_vb(1),
+                &Payment::to(receiver.clone(), Amount::from_sat(10000000)),
+            )
+            .unwrap();
+            sign_psbt(&mut psbt, &signer.secret_bytes()).unwrap();
+
+            let prevouts = psbt_prevouts(&psbt);
+            let tx = finalize_psbt(psbt).unwrap();
+            verify_transaction(&tx, &prevouts).unwrap();
+            let classification = classify_transaction(&tx, &policy);
+            assert_eq!(classification.inputs, vec![spent_type.leaf()]);
+            assert_eq!(classification.tx_type, tx_type);
+            assert!(classification.violations.is_empty(), "{:?}", classification.violations);
+        }
+
+        // the one-way tree has no mulsig or enforce leaf
+        let payment = Payment::to(receiver, Amount::from_sat(10000000));
+        assert!(matches!(
+            build_tx(SpendType::MULSIG, &[policy], out_points, sat_vb(1), &payment),
+            Err(SelfCustodyError::MissingLeaf(Leaf::Mulsig))
+        ));
+    }
+
+    #[test]
+    fn test_channel_params() {
+        // block heights
+        let params = ChannelParams::with_default_escape(800000, 900000).unwrap();
+        assert_eq!(params.escape_time().to_consensus_u32(), 800000 + SIX_MONTHS_BLOCKS);
+        assert!(ChannelParams::new(800000, 826280, 826281).is_ok());
+        assert!(ChannelParams::new(800000, 826279, 900000).is_err());
+        assert!(ChannelParams::new(800000, 826280, 826280).is_err());
+        // an escape lock set years later is a mistake too
+        assert!(matches!(
+            ChannelParams::new(800000, 826280 + 3 * SIX_MONTHS_BLOCKS, 1000000),
+            Err(SelfCustodyError::InvalidTimelock(_))
+        ));
+
+        // timestamps
+        let enforce = 1714567394;
+        let escape = ChannelParams::default_escape_time(enforce).unwrap();
+        assert_eq!(escape, enforce + SIX_MONTHS_SECONDS);
+        assert!(ChannelParams::new(enforce, escape, escape + 1).is_ok());
+        assert!(ChannelParams::new(enforce, escape - 1, escape + 1).is_err());
+        assert!(ChannelParams::new(enforce, escape + 1, escape + 2).is_err());
+
+        // mixed units and locks leaving their unit
+        assert!(matches!(
+            ChannelParams::new(800000, escape, escape + 1),
+            Err(SelfCustodyError::InvalidTimelock(_))
+        ));
+        assert!(ChannelParams::new(enforce, escape, 900000).is_err());
+        assert!(ChannelParams::default_escape_time(499_990_000).is_err());
+        assert!(ChannelParams::default_escape_time(u32::MAX).is_err());
+        assert_eq!(ChannelParams::default_enforce_time(escape).unwrap(), enforce);
+        assert!(ChannelParams::default_enforce_time(500_001_000).is_err());
+        assert!(ChannelParams::check_two_way(800000, 826280).is_ok());
+
+        // the policies of the CLI are checked the same way
+        let secp = Secp256k1::new();
+        let key = Keypair::new(&secp, &mut rand::thread_rng()).public_key().to_string();
+        let policy = |enforce_time, escape_time, lock| {
+            let network = Network::Regtest;
+            custody_policy(&key, &key, &key, enforce_time, escape_time, lock, None, network)
+        };
+        assert!(policy(800000, 826280, LeafLock::Absolute).is_ok());
+        assert!(policy(enforce, escape, LeafLock::Absolute).is_ok());
+        assert!(policy(100, 200, LeafLock::Blocks).is_ok());
+        for (enforce_time, escape_time, lock) in [
+            (200, 100, LeafLock::Absolute),
+            (enforce, 100, LeafLock::Absolute),
+            (800000, 826281, LeafLock::Absolute),
+            (200, 100, LeafLock::Blocks),
+            (100, 100, LeafLock::Intervals),
+        ] {
+            assert!(matches!(
+                policy(enforce_time, escape_time, lock),
+                Err(SelfCustodyError::InvalidTimelock(_))
+            ));
+        }
+        let one_way = |escape_time, enforce_time, lock| {
+            one_way_policy(&key, &key, escape_time, enforce_time, lock, Network::Regtest)
+        };
+        assert!(one_way(900000, Some(800000), LeafLock::Absolute).is_ok());
+        assert!(one_way(100, None, LeafLock::Absolute).is_ok());
+        assert!(one_way(826280, Some(800000), LeafLock::Absolute).is_err());
+        assert!(one_way(100, Some(200), LeafLock::Blocks).is_err());
+    }
+
+    #[test]
+    fn test_channels() {
+        let secp = Secp256k1::new();
+        let owner = Keypair::new(&secp, &mut rand::thread_rng());
+        let committee = Keypair::new(&secp, &mut rand::thread_rng());
+        let validator = Keypair::new(&secp, &mut rand::thread_rng());
+        let params = ChannelParams::with_default_escape(800000, 900000).unwrap();
+
+        let whale = WhaleChannel::new(
+            owner.public_key(),
+            committee.public_key(),
+            validator.public_key(),
+            params,
+            Network::Regtest,
+        )
+        .unwrap();
+        let retail = RetailChannel::new(
+            owner.public_key(),
+            committee.public_key(),
+            validator.public_key(),
+            params,
+            Network::Regtest,
+        )
+        .unwrap();
+        let one_way = OneWayChannel::new(
+            committee.public_key(),
+            validator.public_key(),
+            params,
+            Network::Regtest,
+        );
+        assert_eq!(whale.address(), retail.address());
+        assert_ne!(whale.address(), one_way.address());
+        assert_eq!(
+            whale.spend_paths(),
+            vec![SpendType::MULSIG, SpendType::ENFORCE(800000), SpendType::ESCAPE(826280)]
+        );
+        assert_eq!(
+            one_way.spend_paths(),
+            vec![SpendType::COMMITTEE, SpendType::ESCAPE(900000)]
+        );
+
+        let utxos: Vec<LocalUtxo> = vec![LocalUtxo {
+            txid: "0b78bd9e57b99e83bb1b5f1a1c1ecd8ae801fcb62f41cb62f986b38090354b65".to_string(),
+            vout: 1,
+            amount: 100000000,
+            script_pubkey: Some(whale.policy().script_pubkey().to_hex_string()),
+            height: None,
+        }];
+
+        // whale -> one-way forced withdrawal by the committee
+        let mut psbt = whale.forced_withdrawal(utxos.clone(), sat_vb(1), &one_way).unwrap();
+        sign_psbt(&mut psbt, &committee.secret_bytes()).unwrap();
+        let prevouts = psbt_prevouts(&psbt);
+        let tx = finalize_psbt(psbt).unwrap();
+        verify_transaction(&tx, &prevouts).unwrap();
+        validate_forced_withdrawal(&tx, std::slice::from_ref(one_way.policy())).unwrap();
+
+        // the one-way channel redeems the withdrawn output through the committee
+        let withdrawn = vec![LocalUtxo {
+            txid: tx.txid().to_string(),
+            vout: 0,
+            amount: tx.output[0].value.to_sat(),
+            script_pubkey: Some(tx.output[0].script_pubkey.to_hex_string()),
+            height: None,
+        }];
+        let receiver =
+            Address::from_str("bcrt1pz7y5ps533cnjg8vhgjct6zt4zta8pc9tym6j39v52c37rj8tce7qzrzxj5")
+                .unwrap()
+                .assume_checked();
+        let mut psbt = one_way
+            .redeem(withdrawn.clone(), sat_vb(1), receiver.clone(), 10000000)
+            .unwrap();
+        sign_psbt(&mut psbt, &committee.secret_bytes()).unwrap();
+        let prevouts = psbt_prevouts(&psbt);
+        verify_transaction(&finalize_psbt(psbt).unwrap(), &prevouts).unwrap();
+
+        let mut psbt = one_way.escape(withdrawn, sat_vb(1), receiver, 10000000).unwrap();
+        assert_eq!(psbt.unsigned_tx.lock_time.to_consensus_u32(), 900000);
+        sign_psbt(&mut psbt, &validator.secret_bytes()).unwrap();
+        let prevouts = psbt_prevouts(&psbt);
+        verify_transaction(&finalize_psbt(psbt).unwrap(), &prevouts).unwrap();
+
+        // a one-way channel escaping before the whale channel can't receive its withdrawals
+        let early_params = ChannelParams::with_default_escape(700000, 800000).unwrap();
+        let early = OneWayChannel::new(
+            committee.public_key(),
+            validator.public_key(),
+            early_params,
+            Network::Regtest,
+        );
+        assert!(matches!(
+            whale.forced_withdrawal(utxos, sat_vb(1), &early),
+            Err(SelfCustodyError::InvalidTimelock(_))
+        ));
+    }
+
+    #[test]
+    fn test_spent_with_csv_scripts() {
+        let secp = Secp256k1::new();
+        let keypair1 = Keypair::new(&secp, &mut rand::thread_rng());
+        let keypair2 = Keypair::new(&secp, &mut rand::thread_rng());
+        let s1 = keypair1.secret_bytes().to_vec();
+        let s2 = keypair2.secret_bytes().to_vec();
+        let (a1, a2, sum, _) = create_account(s1.clone(), s2.clone());
+
+        let enforce_lock = relative::LockTime::Blocks(relative::Height::from(144));
+        let escape_lock = relative::LockTime::Time(relative::Time::from_512_second_intervals(4000));
+        let policy = CustodyPolicy::new(
+            sum,
+            build_mulsig2(a1, a2).into_script(),
+            build_enforce_csv(enforce_lock, a1).into_script(),
+            build_escape_csv(escape_lock, a2).into_script(),
+            Network::Regtest,
+        );
+        assert_eq!(
+            script_timelock(policy.script(Leaf::Enforce).unwrap()),
+            Some(LeafTimelock::Relative(enforce_lock))
+        );
+        assert_eq!(
+            script_timelock(policy.script(Leaf::Escape).unwrap()),
+            Some(LeafTimelock::Relative(escape_lock))
+        );
+        assert_eq!(script_timelock(policy.script(Leaf::Mulsig).unwrap()), None);
+
+        // the CLI builds the leaves of both locks in one unit
+        let (pk1, pk2) = (keypair1.public_key().to_string(), keypair2.public_key().to_string());
+        let cli_policy = |time, lock| {
+            custody_policy(&pk1, &pk2, &pk2, 144, time, lock, None, Network::Regtest)
+        };
+        let blocks = cli_policy(4000, LeafLock::Blocks).unwrap();
+        assert_eq!(
+            script_timelock(blocks.script(Leaf::Enforce).unwrap()),
+            Some(LeafTimelock::Relative(enforce_lock))
+        );
+        let intervals = cli_policy(4000, LeafLock::Intervals).unwrap();
+        assert_eq!(
+            script_timelock(intervals.script(Leaf::Escape).unwrap()),
+            Some(LeafTimelock::Relative(escape_lock))
+        );
+        assert!(matches!(
+            cli_policy(70000, LeafLock::Blocks),
+            Err(SelfCustodyError::InvalidTimelock(_))
+        ));
+
+        let out_points: Vec<LocalUtxo> = vec![LocalUtxo {
+            txid: "0b78bd9e57b99e83bb1b5f1a1c1ecd8ae801fcb62f41cb62f986b38090354b65".to_string(),
+            vout: 1,
+            amount: 100000000,
+            script_pubkey: None,
+            height: None,
+        }];
+        let receiver =
+            Address::from_str("bcrt1pz7y5ps533cnjg8vhgjct6zt4zta8pc9tym6j39v52c37rj8tce7qzrzxj5")
+                .unwrap()
+                .assume_checked();
+
+        // the relative locks are read from the scripts, the lock times are unused
+        for (spent_type, lock, secret) in [
+            (SpendType::ENFORCE(100), enforce_lock, &s1),
+            (SpendType::ESCAPE(110), escape_lock, &s2),
+        ] {
+            let mut psbt = build_tx(
+                spent_type,
+                std::slice::from_ref(&policy),
+                out_points.clone(),
+                sat_vb(1),
+                &Payment::to(receiver.clone(), Amount::from_sat(10000000)),
+            )
+            .unwrap();
+            let unsigned_tx = &psbt.unsigned_tx;
+            assert_eq!(unsigned_tx.version, bitcoin::transaction::Version::TWO);
+            assert_eq!(unsigned_tx.lock_time, bitcoin::absolute::LockTime::ZERO);
+            assert_eq!(unsigned_tx.input[0].sequence, relative_sequence(lock));
+
+            sign_psbt(&mut psbt, secret).unwrap();
+            let prevouts = psbt_prevouts(&psbt);
+            let tx = finalize_psbt(psbt).unwrap();
+            verify_transaction(&tx, &prevouts).unwrap();
+
+            // an input spent before its relative lock
+            let mut early_tx = tx.clone();
+            early_tx.input[0].sequence = Sequence::from_consensus(
+                relative_sequence(lock).to_consensus_u32() - 1,
+            );
+            assert!(matches!(
+                verify_transaction(&early_tx, &prevouts),
+                Err(SelfCustodyError::ScriptVerification { input: 0, .. })
+            ));
+        }
+
+        // absolute locks of different units can't share a transaction
+        let new_policy = |escape_time| {
+            CustodyPolicy::new(
+                sum,
+                build_mulsig2(a1, a2).into_script(),
+                build_enforce(100, a1).into_script(),
+                build_escape(escape_time, a2).into_script(),
+                Network::Regtest,
+            )
+        };
+        let policies = [new_policy(110), new_policy(1714567394)];
+        let mixed: Vec<LocalUtxo> = policies
+            .iter()
+            .map(|policy| LocalUtxo {
+                txid: "0b78bd9e57b99e83bb1b5f1a1c1ecd8ae801fcb62f41cb62f986b38090354b65"
+                    .to_string(),
+                vout: 0,
+                amount: 100000000,
+                script_pubkey: Some(policy.script_pubkey().to_hex_string()),
+                height: None,
+            })
+            .collect();
+        let payment = Payment::to(receiver, Amount::from_sat(10000000));
+        assert!(matches!(
+            build_tx(SpendType::ESCAPE(110), &policies, mixed, sat_vb(1), &payment),
+            Err(SelfCustodyError::InvalidTimelock(_))
+        ));
+    }
+
+    #[test]
+    fn test_lock_time_validation() {
+        let secp = Secp256k1::new();
+        let keypair1 = Keypair::new(&secp, &mut rand::thread_rng());
+        let keypair2 = Keypair::new(&secp, &mut rand::thread_rng());
+        let (a1, a2, sum, _) = create_account(
+            keypair1.secret_bytes().to_vec(),
+            keypair2.secret_bytes().to_vec(),
+        );
+        let escape_time = 1714567394;
+        let policy = CustodyPolicy::new(
+            sum,
+            build_mulsig2(a1, a2).into_script(),
+            build_enforce(100, a1).into_script(),
+            build_escape(escape_time, a2).into_script(),
+            Network::Regtest,
+        );
+        let out_points: Vec<LocalUtxo> = vec![LocalUtxo {
+            txid: "0b78bd9e57b99e83bb1b5f1a1c1ecd8ae801fcb62f41cb62f986b38090354b65".to_string(),
+            vout: 1,
+            amount: 100000000,
+            script_pubkey: None,
+            height: None,
+        }];
+        let receiver =
+            Address::from_str("bcrt1pz7y5ps533cnjg8vhgjct6zt4zta8pc9tym6j39v52c37rj8tce7qzrzxj5")
+                .unwrap()
+                .assume_checked();
+        let build = |spent_type| {
+            build_tx(
+                spent_type,
+                std::slice::from_ref(&policy),
+                out_points.clone(),
+                sat_vb(1),
+                &Payment::to(receiver.clone(), Amount::from_sat(10000000)),
+            )
+        };
+
+        // the lock must be in the script's domain and reach it
+        assert!(matches!(build(SpendType::ESCAPE(110)), Err(SelfCustodyError::InvalidTimelock(_))));
+        assert!(matches!(
+            build(SpendType::ESCAPE(escape_time - 1)),
+            Err(SelfCustodyError::InvalidTimelock(_))
+        ));
+        assert!(matches!(build(SpendType::ENFORCE(99)), Err(SelfCustodyError::InvalidTimelock(_))));
+        let psbt = build(SpendType::ESCAPE(escape_time + 1)).unwrap();
+        assert!(psbt.unsigned_tx.is_lock_time_enabled());
+
+        // against the chain tip, timestamps compare with the median time past
+        let tip = |height, median_time_past| ChainTip {
+            height,
+            median_time_past,
+        };
+        let tx = build(SpendType::ESCAPE(escape_time)).unwrap().unsigned_tx;
+        assert!(matches!(
+            check_lock_time(&tx, tip(840000, escape_time)),
+            Err(SelfCustodyError::LockTimeNotReached { .. })
+        ));
+        check_lock_time(&tx, tip(840000, escape_time + 1)).unwrap();
+
+        let tx = build(SpendType::ENFORCE(100)).unwrap().unsigned_tx;
+        assert_eq!(
+            check_lock_time(&tx, tip(99, escape_time)).unwrap_err().exit_code(),
+            10
+        );
+        check_lock_time(&tx, tip(100, 0)).unwrap();
+    }
+
+    #[test]
+    fn test_spent_with_multi_a_script() {
+        let secp = Secp256k1::new();
+        let signers: Vec<Keypair> = (0..3)
+            .map(|_| Keypair::new(&secp, &mut rand::thread_rng()))
+            .collect();
+        let keys: Vec<XOnlyPublicKey> = signers.iter().map(|v| v.x_only_public_key().0).collect();
+        let validator = Keypair::new(&secp, &mut rand::thread_rng());
+
+        assert!(build_multi_a(0, &keys).is_err());
+        assert!(build_multi_a(4, &keys).is_err());
+        let multi_a = build_multi_a(2, &keys).unwrap().into_script();
+        assert_eq!(required_signatures(&multi_a), 2);
+        assert_eq!(required_signatures(&build_mulsig2(keys[0], keys[1]).into_script()), 2);
+
+        // a rotating 2-of-3 committee
+        let policy = CustodyPolicy::new(
+            keys[0],
+            multi_a,
+            build_enforce(100, keys[0]).into_script(),
+            build_escape(110, validator.x_only_public_key().0).into_script(),
+            Network::Regtest,
+        );
+        let out_points: Vec<LocalUtxo> = vec![LocalUtxo {
+            txid: "0b78bd9e57b99e83bb1b5f1a1c1ecd8ae801fcb62f41cb62f986b38090354b65".to_string(),
+            vout: 1,
+            amount: 100000000,
+            script_pubkey: None,
+            height: None,
+        }];
+        let receiver =
+            Address::from_str("bcrt1pz7y5ps533cnjg8vhgjct6zt4zta8pc9tym6j39v52c37rj8tce7qzrzxj5")
+                .unwrap()
+                .assume_checked();
+        let psbt = build_tx(
+            SpendType::MULSIG,
+            std::slice::from_ref(&policy),
+            out_points,
+            sat_vb(1),
+            &Payment::to(receiver, Amount::from_sat(10000000)),
+        )
+        .unwrap();
+        let fee = 100000000 - psbt.unsigned_tx.output.iter().map(|v| v.value.to_sat()).sum::<u64>();
+
+        let signed_by = |indexes: &[usize]| {
+            let mut psbt = psbt.clone();
+            for i in indexes {
+                sign_psbt(&mut psbt, &signers[*i].secret_bytes()).unwrap();
+            }
+            psbt
+        };
+
+        // the second key is absent, its item is an empty push
+        let signed = signed_by(&[0, 2]);
+        let prevouts = psbt_prevouts(&signed);
+        let tx = finalize_psbt(signed).unwrap();
+        verify_transaction(&tx, &prevouts).unwrap();
+        let witness: Vec<&[u8]> = tx.input[0].witness.iter().collect();
+        assert_eq!(witness.len(), 5);
+        assert_eq!(witness[0].len(), 64);
+        assert!(witness[1].is_empty());
+        assert_eq!(witness[2].len(), 64);
+        assert!(fee >= tx.vsize() as u64);
+        // the prediction counts a sighash type byte per signature
+        let predicted = predict_satisfaction_weight(&policy, Some(Leaf::Mulsig)).unwrap();
+        assert_eq!(tx.input[0].witness.size() as u64 + 2, predicted.to_wu());
+
+        // every signer signed, only the threshold is used
+        let signed = signed_by(&[0, 1, 2]);
+        let tx = finalize_psbt(signed).unwrap();
+        verify_transaction(&tx, &prevouts).unwrap();
+        assert!(tx.input[0].witness.iter().next().unwrap().is_empty());
+
+        assert!(matches!(
+            finalize_psbt(signed_by(&[1])),
+            Err(SelfCustodyError::SignatureCountMismatch {
+                input: 0,
+                expected: 2,
+                found: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn test_fee_rate_and_caps() {
+        // rates round up to the next sat/kwu and fees to whole vbytes and the next sat
+        assert_eq!(parse_fee_rate("1").unwrap().to_sat_per_kwu(), 250);
+        assert_eq!(parse_fee_rate("1.5").unwrap().to_sat_per_kwu(), 375);
+        assert_eq!(parse_fee_rate("1.001").unwrap().to_sat_per_kwu(), 251);
+        assert!(parse_fee_rate("1.0001").is_err());
+        assert!(parse_fee_rate("-1").is_err());
+        assert_eq!(sat_vb(1).amount(Weight::from_wu(401)).unwrap(), Amount::from_sat(101));
+        let rate = parse_fee("1.5", None, None, None).unwrap();
+        assert_eq!(rate.amount(Weight::from_wu(401)).unwrap(), Amount::from_sat(152));
+
+        let secp = Secp256k1::new();
+        let keypair1 = Keypair::new(&secp, &mut rand::thread_rng());
+        let keypair2 = Keypair::new(&secp, &mut rand::thread_rng());
+        let (a1, a2, sum, _) = create_account(
+            keypair1.secret_bytes().to_vec(),
+            keypair2.secret_bytes().to_vec(),
+        );
+        let policy = CustodyPolicy::new(
+            sum,
+            build_mulsig2(a1, a2).into_script(),
+            build_enforce(100, a1).into_script(),
+            build_escape(110, a2).into_script(),
+            Network::Regtest,
+        );
+        let out_points: Vec<LocalUtxo> = vec![LocalUtxo {
+            txid: "0b78bd9e57b99e83bb1b5f1a1c1ecd8ae801fcb62f41cb62f986b38090354b65".to_string(),
+            vout: 1,
+            amount: 100000000,
+            script_pubkey: None,
+            height: None,
+        }];
+        let receiver =
+            Address::from_str("bcrt1pz7y5ps533cnjg8vhgjct6zt4zta8pc9tym6j39v52c37rj8tce7qzrzxj5")
+                .unwrap()
+                .assume_checked();
+        let payment = Payment::to(receiver, Amount::from_sat(10000000));
+        let policies = [policy];
+        let build =
+            |fee: Fee| build_tx(SpendType::MULSIG, &policies, out_points.clone(), fee, &payment);
+        let paid = |psbt: &Psbt| {
+            100000000 - psbt.unsigned_tx.output.iter().map(|v| v.value.to_sat()).sum::<u64>()
+        };
+
+        let fee = paid(&build(sat_vb(2)).unwrap());
+        assert_eq!(fee, 2 * paid(&build(sat_vb(1)).unwrap()));
+
+        // the absolute fee overrides the rate
+        let psbt = build(Fee::absolute(Amount::from_sat(5000))).unwrap();
+        assert_eq!(paid(&psbt), 5000);
+
+        // a fee at the caps passes, above them aborts the build
+        build(sat_vb(2).with_max_fee(Amount::from_sat(fee))).unwrap();
+        assert!(matches!(
+            build(sat_vb(2).with_max_fee(Amount::from_sat(fee - 1))),
+            Err(SelfCustodyError::FeeTooHigh(_))
+        ));
+        build(sat_vb(2).with_max_fee_rate(parse_fee_rate("2").unwrap())).unwrap();
+        assert!(matches!(
+            build(sat_vb(3).with_max_fee_rate(parse_fee_rate("2.5").unwrap())),
+            Err(SelfCustodyError::FeeTooHigh(_))
+        ));
+        let capped = parse_fee("1", Some(5000), None, Some("10")).unwrap();
+        assert!(matches!(build(capped), Err(SelfCustodyError::FeeTooHigh(_))));
+    }
+
+    #[test]
+    fn test_predict_satisfaction_weight() {
+        let secp = Secp256k1::new();
+        let keypair1 = Keypair::new(&secp, &mut rand::thread_rng());
+        let keypair2 = Keypair::new(&secp, &mut rand::thread_rng());
+        let s1 = keypair1.secret_bytes().to_vec();
+        let s2 = keypair2.secret_bytes().to_vec();
+        let (a1, a2, sum, _) = create_account(s1.clone(), s2.clone());
+        let policy = CustodyPolicy::new(
+            sum,
+            build_mulsig2(a1, a2).into_script(),
+            build_enforce(100, a1).into_script(),
+            build_escape(110, a2).into_script(),
+            Network::Regtest,
+        );
+        let out_points: Vec<LocalUtxo> = vec![LocalUtxo {
+            txid: "0b78bd9e57b99e83bb1b5f1a1c1ecd8ae801fcb62f41cb62f986b38090354b65".to_string(),
+            vout: 1,
+            amount: 100000000,
+            script_pubkey: None,
+            height: None,
+        }];
+        let receiver =
+            Address::from_str("bcrt1pz7y5ps533cnjg8vhgjct6zt4zta8pc9tym6j39v52c37rj8tce7qzrzxj5")
+                .unwrap()
+                .assume_checked();
+        let payment = Payment::to(receiver, Amount::from_sat(10000000));
+
+        // item count and a signature with its sighash type byte
+        let key_path = predict_satisfaction_weight(&policy, None).unwrap();
+        assert_eq!(key_path, Weight::from_wu(1 + 1 + 65));
+
+        let cases = [
+            (SpendType::MULSIG, vec![&s1, &s2]),
+            (SpendType::ESCAPE(110), vec![&s2]),
+            (SpendType::ENFORCE(100), vec![&s1]),
+        ];
+        for (spent_type, signers) in cases {
+            let predicted = predict_satisfaction_weight(&policy, spent_type.leaf()).unwrap();
+            let policies = std::slice::from_ref(&policy);
+            let mut psbt =
+                build_tx(spent_type, policies, out_points.clone(), sat_vb(1), &payment).unwrap();
+            let fee = 100000000
+                - psbt.unsigned_tx.output.iter().map(|v| v.value.to_sat()).sum::<u64>();
+
+            // the worst case is exact with a non-default sighash type
+            psbt.inputs[0].sighash_type = Some(TapSighashType::All.into());
+            for secret in signers {
+                sign_psbt(&mut psbt, secret).unwrap();
+            }
+            let prevouts = psbt_prevouts(&psbt);
+            let tx = finalize_psbt(psbt).unwrap();
+            verify_transaction(&tx, &prevouts).unwrap();
+            assert_eq!(tx.input[0].witness.size() as u64, predicted.to_wu());
+            assert_eq!(fee, tx.vsize() as u64);
+        }
+    }
+
+    #[test]
+    fn test_bump_fee() {
+        // the replacement pays the original, its own relay, and above the original rate
+        let fee = sat_vb(1).replacing(Amount::from_sat(1000), Weight::from_wu(800));
+        assert_eq!(fee.amount(Weight::from_wu(800)).unwrap(), Amount::from_sat(1200));
+        assert_eq!(fee.amount(Weight::from_wu(4000)).unwrap(), Amount::from_sat(5001));
+
+        let secp = Secp256k1::new();
+        let keypair1 = Keypair::new(&secp, &mut rand::thread_rng());
+        let keypair2 = Keypair::new(&secp, &mut rand::thread_rng());
+        let s1 = keypair1.secret_bytes().to_vec();
+        let s2 = keypair2.secret_bytes().to_vec();
+        let (a1, a2, sum, _) = create_account(s1.clone(), s2.clone());
+        let policy = CustodyPolicy::new(
+            sum,
+            build_mulsig2(a1, a2).into_script(),
+            build_enforce(100, a1).into_script(),
+            build_escape(110, a2).into_script(),
+            Network::Regtest,
+        );
+        let policies = [policy];
+        let utxo = |vout: u32, amount: u64| LocalUtxo {
+            txid: "0b78bd9e57b99e83bb1b5f1a1c1ecd8ae801fcb62f41cb62f986b38090354b65".to_string(),
+            vout,
+            amount,
+            script_pubkey: None,
+            height: Some(100),
+        };
+        let receiver =
+            Address::from_str("bcrt1pz7y5ps533cnjg8vhgjct6zt4zta8pc9tym6j39v52c37rj8tce7qzrzxj5")
+                .unwrap()
+                .assume_checked();
+        let escape = |utxos: Vec<LocalUtxo>, fee: Fee, payment: &Payment| {
+            build_tx(SpendType::ESCAPE(110), &policies, utxos, fee, payment).unwrap()
+        };
+        let pay = |amount: u64| Payment::to(receiver.clone(), Amount::from_sat(amount));
+        let signed = |mut psbt: Psbt| {
+            sign_psbt(&mut psbt, &s2).unwrap();
+            let prevouts = psbt_prevouts(&psbt);
+            let tx = finalize_psbt(psbt).unwrap();
+            verify_transaction(&tx, &prevouts).unwrap();
+            (tx, prevouts)
+        };
+        let fee_of = |tx: &Transaction, prevouts: &[TxOut]| {
+            let spent: u64 = prevouts.iter().map(|v| v.value.to_sat()).sum();
+            spent - tx.output.iter().map(|v| v.value.to_sat()).sum::<u64>()
+        };
+        // the transaction stuck at 1 sat/vB
+        let broadcast = |payment: &Payment| {
+            signed(escape(vec![utxo(1, 100000000)], sat_vb(1), payment))
+        };
+        let change = BumpChange::Output(1);
+
+        let (original, prevouts) = broadcast(&pay(10000000));
+        let original_fee = fee_of(&original, &prevouts);
+        let psbt = bump_fee(&original, &prevouts, sat_vb(1), &policies, &change, vec![]).unwrap();
+        assert_eq!(psbt.unsigned_tx.input, {
+            let mut inputs = original.input.clone();
+            inputs.iter_mut().for_each(|v| v.witness = Witness::new());
+            inputs
+        });
+        assert_eq!(psbt.unsigned_tx.lock_time, original.lock_time);
+        assert_eq!(psbt.unsigned_tx.output[0], original.output[0]);
+        let (replacement, prevouts) = signed(psbt);
+        let fee = fee_of(&replacement, &prevouts);
+        assert!(fee >= original_fee + replacement.vsize() as u64);
+        assert!(fee * original.weight().to_wu() > original_fee * replacement.weight().to_wu());
+
+        // the change can't pay 50 sat/vB, a confirmed utxo is added
+        let (original, prevouts) = broadcast(&pay(99995000));
+        let extra = vec![utxo(2, 1000), utxo(3, 5000000)];
+        let psbt = bump_fee(&original, &prevouts, sat_vb(50), &policies, &change, extra).unwrap();
+        assert_eq!(psbt.unsigned_tx.input.len(), 2);
+        assert_eq!(psbt.unsigned_tx.input[1].previous_output.vout, 3);
+        let (replacement, prevouts) = signed(psbt);
+        assert!(fee_of(&replacement, &prevouts) >= 50 * replacement.vsize() as u64);
+        let prevouts = &prevouts[..1];
+        assert!(matches!(
+            bump_fee(&original, prevouts, sat_vb(50), &policies, &change, vec![]),
+            Err(SelfCustodyError::InsufficientFunds { .. })
+        ));
+        // BIP-125 rule 2, a replacement spends no new unconfirmed input
+        let unconfirmed = LocalUtxo {
+            height: None,
+            ..utxo(3, 5000000)
+        };
+        assert!(matches!(
+            bump_fee(&original, prevouts, sat_vb(50), &policies, &change, vec![unconfirmed]),
+            Err(SelfCustodyError::InvalidUtxo(_))
+        ));
+
+        // the caps still apply, and a final transaction can't be replaced
+        let capped = sat_vb(50).with_max_fee(Amount::from_sat(5000));
+        assert!(matches!(
+            bump_fee(&original, prevouts, capped, &policies, &change, vec![utxo(3, 5000000)]),
+            Err(SelfCustodyError::FeeTooHigh(_))
+        ));
+        let mut final_tx = original.clone();
+        final_tx.input[0].sequence = Sequence::MAX;
+        assert!(matches!(
+            bump_fee(&final_tx, prevouts, sat_vb(50), &policies, &change, vec![]),
+            Err(SelfCustodyError::InvalidTransaction(_))
+        ));
+        assert!(matches!(
+            bump_fee(&original, prevouts, sat_vb(50), &policies, &BumpChange::Output(2), vec![]),
+            Err(SelfCustodyError::InvalidTransaction(_))
+        ));
+
+        // a payment whose dust change was dropped: the recipient keeps its amount and a new
+        // change output takes what the added utxo leaves
+        let unsigned = escape(vec![utxo(1, 100000000)], sat_vb(1), &pay(10000000));
+        let amount = 100000000 - fee_of(&unsigned.unsigned_tx, &psbt_prevouts(&unsigned)) - 100;
+        let (original, prevouts) = broadcast(&pay(amount));
+        assert_eq!(original.output.len(), 1);
+        let change = BumpChange::New(policies[0].script_pubkey());
+        let extra = vec![utxo(3, 5000000)];
+        let psbt = bump_fee(&original, &prevouts, sat_vb(5), &policies, &change, extra).unwrap();
+        assert_eq!(psbt.unsigned_tx.input.len(), 2);
+        assert_eq!(psbt.unsigned_tx.output[0], original.output[0]);
+        assert_eq!(psbt.unsigned_tx.output[1].script_pubkey, policies[0].script_pubkey());
+
+        // the change went to an address outside the policies, it still pays the fee
+        let external = Address::from_str("bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080")
+            .unwrap()
+            .assume_checked();
+        let (original, prevouts) = broadcast(&pay(10000000).with_change(external.clone()));
+        let change = BumpChange::Output(1);
+        let psbt = bump_fee(&original, &prevouts, sat_vb(5), &policies, &change, vec![]).unwrap();
+        let (replacement, prevouts) = signed(psbt);
+        assert_eq!(replacement.output.len(), 2);
+        assert_eq!(replacement.output[0], original.output[0]);
+        assert_eq!(replacement.output[1].script_pubkey, external.script_pubkey());
+        assert!(replacement.output[1].value < original.output[1].value);
+        assert!(fee_of(&replacement, &prevouts) >= 5 * replacement.vsize() as u64);
+
+        // a sweep pays the higher fee out of its only output
+        let (original, prevouts) = broadcast(&Payment::sweep(receiver.clone()));
+        let sweep = BumpChange::Sweep(0);
+        let psbt = bump_fee(&original, &prevouts, sat_vb(5), &policies, &sweep, vec![]).unwrap();
+        assert_eq!(psbt.unsigned_tx.output.len(), 1);
+        assert!(psbt.unsigned_tx.output[0].value < original.output[0].value);
+    }
 }
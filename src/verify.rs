@@ -0,0 +1,314 @@
+use bitcoin::absolute::LockTime;
+use bitcoin::consensus::encode;
+use bitcoin::opcodes::all::*;
+use bitcoin::opcodes::Opcode;
+use bitcoin::script::{Instruction, Script};
+use bitcoin::secp256k1::{Message, Secp256k1, VerifyOnly, XOnlyPublicKey};
+use bitcoin::sighash::{Annex, Prevouts, SighashCache};
+use bitcoin::taproot::{self, ControlBlock, LeafVersion, TAPROOT_ANNEX_PREFIX};
+use bitcoin::{Sequence, TapLeafHash, Transaction, TxOut};
+
+use crate::error::{Result, SelfCustodyError};
+
+/// Runs every input of `tx` through script verification against the outputs it spends.
+///
+/// The linked libbitcoinconsensus predates taproot and treats witness v1 outputs as anyone can
+/// spend, so taproot inputs are additionally checked here: the key path signature, or the
+/// control block commitment and the tapscript of the leaf. Only the opcodes used by the custody
+/// leaves are supported.
+pub fn verify_transaction(tx: &Transaction, prevouts: &[TxOut]) -> Result<()> {
+    if tx.input.len() != prevouts.len() {
+        return Err(SelfCustodyError::InvalidTransaction(format!(
+            "{} inputs but {} prevouts",
+            tx.input.len(),
+            prevouts.len()
+        )));
+    }
+
+    let serialized_tx = encode::serialize(tx);
+    for (i, prevout) in prevouts.iter().enumerate() {
+        bitcoin::consensus::verify_script(
+            &prevout.script_pubkey,
+            i,
+            prevout.value,
+            &serialized_tx,
+        )
+        .map_err(|e| script_error(i, e.to_string()))?;
+    }
+
+    let secp = Secp256k1::verification_only();
+    let mut sighasher = SighashCache::new(tx);
+    for (i, prevout) in prevouts.iter().enumerate() {
+        if prevout.script_pubkey.is_p2tr() {
+            verify_taproot_input(&secp, &mut sighasher, tx, i, prevouts)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn script_error(input: usize, reason: impl Into<String>) -> SelfCustodyError {
+    SelfCustodyError::ScriptVerification {
+        input,
+        reason: reason.into(),
+    }
+}
+
+fn verify_taproot_input<'b>(
+    secp: &Secp256k1<VerifyOnly>,
+    sighasher: &mut SighashCache<&'b Transaction>,
+    tx: &'b Transaction,
+    i: usize,
+    prevouts: &[TxOut],
+) -> Result<()> {
+    let output_key = XOnlyPublicKey::from_slice(&prevouts[i].script_pubkey.as_bytes()[2..])
+        .map_err(|e| script_error(i, e.to_string()))?;
+
+    let mut stack: Vec<Vec<u8>> = tx.input[i].witness.iter().map(|v| v.to_vec()).collect();
+    let annex = match stack.last() {
+        Some(last) if stack.len() >= 2 && last.first() == Some(&TAPROOT_ANNEX_PREFIX) => {
+            stack.pop()
+        }
+        _ => None,
+    };
+    let annex = annex
+        .as_deref()
+        .map(Annex::new)
+        .transpose()
+        .map_err(|e| script_error(i, e.to_string()))?;
+
+    if stack.is_empty() {
+        return Err(script_error(i, "empty witness"));
+    }
+
+    // key path
+    if stack.len() == 1 {
+        let sig = taproot::Signature::from_slice(&stack[0])
+            .map_err(|e| script_error(i, e.to_string()))?;
+        let sighash = sighasher
+            .taproot_signature_hash(i, &Prevouts::All(prevouts), annex, None, sig.hash_ty)
+            .map_err(|e| SelfCustodyError::Sighash(e.to_string()))?;
+        return secp
+            .verify_schnorr(&sig.sig, &Message::from(sighash), &output_key)
+            .map_err(|_| script_error(i, "invalid key path signature"));
+    }
+
+    // script path
+    let control_block =
+        ControlBlock::decode(&stack.pop().expect("at least two witness items"))
+            .map_err(|e| script_error(i, e.to_string()))?;
+    let script = bitcoin::ScriptBuf::from(stack.pop().expect("at least two witness items"));
+    if control_block.leaf_version != LeafVersion::TapScript {
+        return Err(script_error(i, "unknown leaf version"));
+    }
+    if !control_block.verify_taproot_commitment(secp, output_key, &script) {
+        return Err(script_error(i, "control block does not commit to the output key"));
+    }
+
+    let leaf_hash = TapLeafHash::from_script(&script, LeafVersion::TapScript);
+    let mut interpreter = Interpreter {
+        secp,
+        sighasher,
+        tx,
+        input: i,
+        prevouts,
+        annex,
+        leaf_hash,
+        stack,
+    };
+    interpreter.execute(&script)?;
+
+    let stack = interpreter.stack;
+    if stack.len() != 1 || !cast_to_bool(&stack[0]) {
+        return Err(script_error(i, "script did not leave a single true element"));
+    }
+    Ok(())
+}
+
+/// A tapscript interpreter for the leaves this crate builds.
+struct Interpreter<'a, 'b> {
+    secp: &'a Secp256k1<VerifyOnly>,
+    sighasher: &'a mut SighashCache<&'b Transaction>,
+    tx: &'b Transaction,
+    input: usize,
+    prevouts: &'a [TxOut],
+    annex: Option<Annex<'a>>,
+    leaf_hash: TapLeafHash,
+    stack: Vec<Vec<u8>>,
+}
+
+impl<'a, 'b> Interpreter<'a, 'b> {
+    fn execute(&mut self, script: &Script) -> Result<()> {
+        for ins in script.instructions_minimal() {
+            match ins.map_err(|e| self.error(e.to_string()))? {
+                Instruction::PushBytes(bytes) => self.stack.push(bytes.as_bytes().to_vec()),
+                Instruction::Op(op) => self.execute_op(op)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn execute_op(&mut self, op: Opcode) -> Result<()> {
+        match op {
+            OP_PUSHNUM_NEG1 => self.stack.push(encode_num(-1)),
+            op if (OP_PUSHNUM_1.to_u8()..=OP_PUSHNUM_16.to_u8()).contains(&op.to_u8()) => {
+                let n = op.to_u8() - OP_PUSHNUM_1.to_u8() + 1;
+                self.stack.push(encode_num(n as i64));
+            }
+            OP_DROP => {
+                self.pop()?;
+            }
+            OP_VERIFY => {
+                let top = self.pop()?;
+                if !cast_to_bool(&top) {
+                    return Err(self.error("OP_VERIFY failed"));
+                }
+            }
+            OP_CHECKSIG | OP_CHECKSIGVERIFY => {
+                let key = self.pop()?;
+                let sig = self.pop()?;
+                let success = self.check_sig(&sig, &key)?;
+                if op == OP_CHECKSIGVERIFY {
+                    if !success {
+                        return Err(self.error("OP_CHECKSIGVERIFY failed"));
+                    }
+                } else {
+                    self.stack.push(encode_num(success as i64));
+                }
+            }
+            OP_CHECKSIGADD => {
+                let key = self.pop()?;
+                let n = self.pop_num(4)?;
+                let sig = self.pop()?;
+                let success = self.check_sig(&sig, &key)?;
+                self.stack.push(encode_num(n + success as i64));
+            }
+            OP_NUMEQUAL | OP_NUMEQUALVERIFY => {
+                let b = self.pop_num(4)?;
+                let a = self.pop_num(4)?;
+                if op == OP_NUMEQUALVERIFY {
+                    if a != b {
+                        return Err(self.error("OP_NUMEQUALVERIFY failed"));
+                    }
+                } else {
+                    self.stack.push(encode_num((a == b) as i64));
+                }
+            }
+            OP_CLTV => {
+                let n = self.peek_num(5)?;
+                if n < 0 || n > u32::MAX as i64 {
+                    return Err(self.error("negative or oversized locktime"));
+                }
+                let lock = LockTime::from_consensus(n as u32);
+                if !lock.is_implied_by(self.tx.lock_time) {
+                    return Err(self.error("transaction locktime does not satisfy OP_CLTV"));
+                }
+                if self.tx.input[self.input].sequence == Sequence::MAX {
+                    return Err(self.error("OP_CLTV input has a final sequence"));
+                }
+            }
+            OP_CSV => {
+                let n = self.peek_num(5)?;
+                if n < 0 || n > u32::MAX as i64 {
+                    return Err(self.error("negative or oversized sequence"));
+                }
+                // the disable flag turns OP_CSV into a no-op
+                if let Some(lock) = Sequence::from_consensus(n as u32).to_relative_lock_time() {
+                    if self.tx.version.0 < 2 {
+                        return Err(self.error("OP_CSV needs transaction version 2"));
+                    }
+                    let satisfied = self.tx.input[self.input]
+                        .sequence
+                        .to_relative_lock_time()
+                        .map(|seq| lock.is_implied_by(seq))
+                        .unwrap_or(false);
+                    if !satisfied {
+                        return Err(self.error("input sequence does not satisfy OP_CSV"));
+                    }
+                }
+            }
+            op => return Err(self.error(format!("unsupported opcode {}", op))),
+        }
+        Ok(())
+    }
+
+    /// BIP-342 signature check, an empty signature is a failed check rather than an error.
+    fn check_sig(&mut self, sig: &[u8], key: &[u8]) -> Result<bool> {
+        if key.len() != 32 {
+            return Err(self.error("public key is not 32 bytes"));
+        }
+        if sig.is_empty() {
+            return Ok(false);
+        }
+        let key = XOnlyPublicKey::from_slice(key).map_err(|e| self.error(e.to_string()))?;
+        let sig = taproot::Signature::from_slice(sig).map_err(|e| self.error(e.to_string()))?;
+        let sighash = self
+            .sighasher
+            .taproot_signature_hash(
+                self.input,
+                &Prevouts::All(self.prevouts),
+                self.annex.clone(),
+                Some((self.leaf_hash, u32::MAX)),
+                sig.hash_ty,
+            )
+            .map_err(|e| SelfCustodyError::Sighash(e.to_string()))?;
+        self.secp
+            .verify_schnorr(&sig.sig, &Message::from(sighash), &key)
+            .map_err(|_| self.error(format!("invalid signature for {}", key)))?;
+        Ok(true)
+    }
+
+    fn pop(&mut self) -> Result<Vec<u8>> {
+        self.stack.pop().ok_or_else(|| self.error("stack underflow"))
+    }
+
+    fn pop_num(&mut self, max_len: usize) -> Result<i64> {
+        let top = self.pop()?;
+        decode_num(&top, max_len).ok_or_else(|| self.error("invalid script number"))
+    }
+
+    fn peek_num(&self, max_len: usize) -> Result<i64> {
+        let top = self.stack.last().ok_or_else(|| self.error("stack underflow"))?;
+        decode_num(top, max_len).ok_or_else(|| self.error("invalid script number"))
+    }
+
+    fn error(&self, reason: impl Into<String>) -> SelfCustodyError {
+        script_error(self.input, reason)
+    }
+}
+
+fn cast_to_bool(v: &[u8]) -> bool {
+    match v.split_last() {
+        None => false,
+        Some((last, rest)) => rest.iter().any(|b| *b != 0) || (*last != 0 && *last != 0x80),
+    }
+}
+
+fn encode_num(n: i64) -> Vec<u8> {
+    let mut buf = [0u8; 8];
+    let len = bitcoin::script::write_scriptint(&mut buf, n);
+    buf[..len].to_vec()
+}
+
+/// Decodes a minimally encoded script number of at most `max_len` bytes.
+fn decode_num(v: &[u8], max_len: usize) -> Option<i64> {
+    if v.len() > max_len {
+        return None;
+    }
+    let (last, _) = match v.split_last() {
+        None => return Some(0),
+        Some(split) => split,
+    };
+    if last & 0x7f == 0 && (v.len() == 1 || v[v.len() - 2] & 0x80 == 0) {
+        return None;
+    }
+    let mut n: i64 = 0;
+    for (i, b) in v.iter().enumerate() {
+        n |= (*b as i64) << (8 * i);
+    }
+    if last & 0x80 != 0 {
+        n &= !(0x80 << (8 * (v.len() - 1)));
+        n = -n;
+    }
+    Some(n)
+}